@@ -3,6 +3,48 @@ CLK_NORMALIZED_COORDS_FALSE |
 CLK_ADDRESS_NONE |
 CLK_FILTER_NEAREST;
 
+// What neighbors past the edge of the canvas are. The sampler can only wrap and
+// mirror normalized coordinates, so kernels move their reads onto the canvas
+// with boundary_loc instead. Keep in sync with Boundary in config.rs and sim.rs.
+#define BOUNDARY_NONE 0
+#define BOUNDARY_CLAMP 1
+#define BOUNDARY_WRAP 2
+#define BOUNDARY_MIRROR 3
+
+// Positive remainder.
+int boundary_mod(int v, int len) {
+    const int m = v % len;
+    return m < 0 ? m + len : m;
+}
+
+int boundary_axis(int v, int len, int boundary) {
+    switch (boundary) {
+    case BOUNDARY_CLAMP: return clamp(v, 0, len - 1);
+    case BOUNDARY_WRAP: return boundary_mod(v, len);
+    case BOUNDARY_MIRROR: {
+        const int m = boundary_mod(v, 2 * len);
+        return m < len ? m : 2 * len - 1 - m;
+    }
+    default: return v;
+    }
+}
+
+// How far apart two pixels are. Around a torus when the canvas wraps,
+// so nothing about the distance gives away where the edges are.
+float boundary_distance(int2 a, int2 b, int2 dims, int boundary) {
+    float2 d = fabs(convert_float2(a - b));
+    if (boundary == BOUNDARY_WRAP) {
+        d = fmin(d, convert_float2(dims) - d);
+    }
+    return length(d);
+}
+
+// Move `loc` onto a canvas of `dims`. False if it falls off.
+bool boundary_loc(int2 *loc, int2 dims, int boundary) {
+    *loc = (int2)(boundary_axis(loc->x, dims.x, boundary), boundary_axis(loc->y, dims.y, boundary));
+    return loc->x >= 0 && loc->y >= 0 && loc->x < dims.x && loc->y < dims.y;
+}
+
 __kernel void clove(write_only image2d_t dest) {
     const int2 pixel_id = (int2)(get_global_id(0), get_global_id(1));
     const int2 dims = get_image_dim(dest);
@@ -19,13 +61,14 @@ __kernel void passthru(read_only image2d_t source, write_only image2d_t dest) {
     write_imagef(dest, pixel_id, rgba);
 }
 
-__kernel void march_penguins(read_only image2d_t source, write_only image2d_t dest) {
+__kernel void march_penguins(read_only image2d_t source, int boundary, write_only image2d_t dest) {
     const int2 pixel_id = (int2)(get_global_id(0), get_global_id(1));
     const int2 dims = get_image_dim(dest);
     float4 rgba = read_imagef(source, sampler_const, pixel_id);
-    if (pixel_id.x > 0) {
+    int2 loc = pixel_id + (int2)(-1,0);
+    if (boundary_loc(&loc, dims, boundary)) {
         // scoot
-        float4 rgba_neighbor = read_imagef(source, sampler_const, pixel_id + (int2)(-1,0));
+        float4 rgba_neighbor = read_imagef(source, sampler_const, loc);
         rgba = (rgba + rgba_neighbor) / 2;
     }
     if (abs(pixel_id.x - 40) <= 10 && abs(pixel_id.y - 40) <= 10) {
@@ -35,48 +78,89 @@ __kernel void march_penguins(read_only image2d_t source, write_only image2d_t de
     write_imagef(dest, pixel_id, rgba);
 }
 
-__kernel void life(read_only image2d_t source, write_only image2d_t dest) {
+// Life-like cellular automata. The mask holds each cell's state: 0 dead, 1 alive,
+// and for Generations rules (states > 2) 2 and up are dying, counting up back to 0.
+// Only live cells count as neighbors.
+// birth and survive are bit sets of neighbor counts, 0 to 127 over four ints.
+// Colors are packed 0xRRGGBB.
+// Keep in sync with life.rs and the Life mode.
+#define LIFE_MOORE 0
+#define LIFE_VON_NEUMANN 1
+
+bool life_has(int4 set, int count) {
+    int word = set.x;
+    if (count >= 96) {
+        word = set.w;
+    } else if (count >= 64) {
+        word = set.z;
+    } else if (count >= 32) {
+        word = set.y;
+    }
+    return ((uint)word >> (count % 32)) & 1;
+}
+
+float4 life_unpack(int rgb) {
+    return convert_float4((int4)((rgb >> 16) & 255, (rgb >> 8) & 255, rgb & 255, 255)) / 255.0f;
+}
+
+// Dying cells fade from live towards dead.
+float4 life_color(uint state, int states, int live_rgb, int dead_rgb) {
+    const float4 live = life_unpack(live_rgb);
+    const float4 dead = life_unpack(dead_rgb);
+    if (state == 0) {
+        return dead;
+    }
+    return mix(live, dead, (float)(state - 1) / (states - 1));
+}
+
+__kernel void life(
+    read_only image2d_t in_mask,
+    int birth0, int birth1, int birth2, int birth3,
+    int survive0, int survive1, int survive2, int survive3,
+    int states,
+    int neighborhood,
+    int radius,
+    int boundary,
+    int live_rgb,
+    int dead_rgb,
+    write_only image2d_t out_canvas,
+    write_only image2d_t out_mask)
+{
     const int2 pixel_id = (int2)(get_global_id(0), get_global_id(1));
-    const int2 dims = get_image_dim(dest);
-    const float4 src_rgba = read_imagef(source, sampler_const, pixel_id);
-    bool live = src_rgba.y > .5;
+    const int2 dims = get_image_dim(out_canvas);
+    const uint state = read_imageui(in_mask, sampler_const, pixel_id).x;
+
     int live_neighbors = 0;
-    for (int dx = -1; dx <= 1;  dx++) {
-        for (int dy = -1; dy <= 1;  dy++) {
-            const int2 loc = pixel_id + (int2)(dx, dy);
+    for (int dy = -radius; dy <= radius; dy++) {
+        for (int dx = -radius; dx <= radius; dx++) {
+            int2 loc = pixel_id + (int2)(dx, dy);
             bool self = (dx == 0 && dy == 0);
-            bool in_bounds = (loc.x >= 0 && loc.y >= 0 && loc.x < dims.x && loc.y < dims.y);
-            // bool select = (abs(loc.x - 10) < 5) && (abs(loc.y - 10) < 5);
-            // bool select = true;
-            if (!self && in_bounds) {
-                const float4 rgba_neighbor = read_imagef(source, sampler_const, loc);
-                if (rgba_neighbor.y > .5) {
+            bool in_shape = neighborhood != LIFE_VON_NEUMANN || abs(dx) + abs(dy) <= radius;
+            if (!self && in_shape && boundary_loc(&loc, dims, boundary)) {
+                if (read_imageui(in_mask, sampler_const, loc).x == 1) {
                     live_neighbors += 1;
                 }
             }
         }
     }
-    // if (live_neighbors > 0) {
-    // 	printf("px: %d %d: live:%d neighbors:%d\n", pixel_id.x, pixel_id.y,
-    // 	       live, live_neighbors);
-    // }
-    if (live_neighbors == 3 || (live && live_neighbors == 2)) {
-        live = true;
-    } else {
-    live = false;
-    }
-    float4 dest_rgba = (float4)(.2,0,0,1);
-    if (live) {
-        dest_rgba = (float4)(0,1,1,1);
+
+    uint next = 0;
+    if (state == 0) {
+        if (life_has((int4)(birth0, birth1, birth2, birth3), live_neighbors)) {
+            next = 1;
+        }
+    } else if (state == 1) {
+        if (life_has((int4)(survive0, survive1, survive2, survive3), live_neighbors)) {
+            next = 1;
+        } else if (states > 2) {
+            next = 2;
+        }
+    } else if (state + 1 < states) {
+        next = state + 1;
     }
-    // if (abs(pixel_id.x - 5) <= 2 && abs(pixel_id.y - 5) <= 2) {
-    //     dest_rgba = (float4)(1,1,1,1);
-    // }
-    write_imagef(dest, pixel_id, dest_rgba);
-    // if (pixel_id.x < 0) {
-    // 	// Should neve happens. Just here to work around some bug.
-    //     printf("px: %f %f %f %f\n", src_rgba.x, src_rgba.y, src_rgba.z, src_rgba.w);
-    // }
+
+    write_imagef(out_canvas, pixel_id, life_color(next, states, live_rgb, dead_rgb));
+    write_imageui(out_mask, pixel_id, (uint4)(next, 0, 0, 0));
 }
 
 // __kernel void life(read_only image2d_t source, write_only image2d_t dest) {
@@ -101,26 +185,185 @@ __kernel void life(read_only image2d_t source, write_only image2d_t dest) {
 //     }
 // }
 
+// Color spaces for the random walk and for comparing colors.
+// Each is scaled so black to white is 1.
+// Keep in sync with ColorSpace in config.rs and color.rs.
+#define COLOR_SPACE_SRGB 0
+#define COLOR_SPACE_LINEAR 1
+#define COLOR_SPACE_LAB 2
+#define COLOR_SPACE_OKLAB 3
+#define COLOR_SPACE_HSV 4
+
+float srgb_to_linear1(float c) {
+    return c <= 0.04045f ? c / 12.92f : pow((c + 0.055f) / 1.055f, 2.4f);
+}
+
+float linear_to_srgb1(float c) {
+    return c <= 0.0031308f ? c * 12.92f : 1.055f * pow(c, 1.0f / 2.4f) - 0.055f;
+}
+
+float3 srgb_to_linear(float3 c) {
+    return (float3)(srgb_to_linear1(c.x), srgb_to_linear1(c.y), srgb_to_linear1(c.z));
+}
+
+// Negative channels are out of gamut anyway, keep their sign so they stay that way.
+float3 linear_to_srgb(float3 c) {
+    return (float3)(
+        sign(c.x) * linear_to_srgb1(fabs(c.x)),
+        sign(c.y) * linear_to_srgb1(fabs(c.y)),
+        sign(c.z) * linear_to_srgb1(fabs(c.z)));
+}
+
+// CIELAB with a D65 white.
+#define LAB_DELTA (6.0f / 29.0f)
+
+float lab_f(float t) {
+    return t > LAB_DELTA * LAB_DELTA * LAB_DELTA ? cbrt(t) : t / (3 * LAB_DELTA * LAB_DELTA) + 4.0f / 29.0f;
+}
+
+float lab_f_inv(float t) {
+    return t > LAB_DELTA ? t * t * t : 3 * LAB_DELTA * LAB_DELTA * (t - 4.0f / 29.0f);
+}
+
+// L, a and b over 100.
+float3 linear_to_lab(float3 c) {
+    const float x = (0.4124564f * c.x + 0.3575761f * c.y + 0.1804375f * c.z) / 0.95047f;
+    const float y = 0.2126729f * c.x + 0.7151522f * c.y + 0.0721750f * c.z;
+    const float z = (0.0193339f * c.x + 0.1191920f * c.y + 0.9503041f * c.z) / 1.08883f;
+    const float fx = lab_f(x);
+    const float fy = lab_f(y);
+    const float fz = lab_f(z);
+    return (float3)(1.16f * fy - 0.16f, 5 * (fx - fy), 2 * (fy - fz));
+}
+
+float3 lab_to_linear(float3 lab) {
+    const float fy = (lab.x + 0.16f) / 1.16f;
+    const float x = lab_f_inv(fy + lab.y / 5) * 0.95047f;
+    const float y = lab_f_inv(fy);
+    const float z = lab_f_inv(fy - lab.z / 2) * 1.08883f;
+    return (float3)(
+        3.2404542f * x - 1.5371385f * y - 0.4985314f * z,
+        -0.9692660f * x + 1.8760108f * y + 0.0415560f * z,
+        0.0556434f * x - 0.2040259f * y + 1.0572252f * z);
+}
+
+// https://bottosson.github.io/posts/oklab/
+float3 linear_to_oklab(float3 c) {
+    const float l = cbrt(0.4122214708f * c.x + 0.5363325363f * c.y + 0.0514459929f * c.z);
+    const float m = cbrt(0.2119034982f * c.x + 0.6806995451f * c.y + 0.1073969566f * c.z);
+    const float s = cbrt(0.0883024619f * c.x + 0.2817188376f * c.y + 0.6299787005f * c.z);
+    return (float3)(
+        0.2104542553f * l + 0.7936177850f * m - 0.0040720468f * s,
+        1.9779984951f * l - 2.4285922050f * m + 0.4505937099f * s,
+        0.0259040371f * l + 0.7827717662f * m - 0.8086757660f * s);
+}
+
+float3 oklab_to_linear(float3 lab) {
+    const float l_ = lab.x + 0.3963377774f * lab.y + 0.2158037573f * lab.z;
+    const float m_ = lab.x - 0.1055613458f * lab.y - 0.0638541728f * lab.z;
+    const float s_ = lab.x - 0.0894841775f * lab.y - 1.2914855480f * lab.z;
+    const float l = l_ * l_ * l_;
+    const float m = m_ * m_ * m_;
+    const float s = s_ * s_ * s_;
+    return (float3)(
+        4.0767416621f * l - 3.3077115913f * m + 0.2309699292f * s,
+        -1.2684380046f * l + 2.6097574011f * m - 0.3413193965f * s,
+        -0.0041960863f * l - 0.7034186147f * m + 1.7076147010f * s);
+}
+
+// HSV as a cone: value is the height and saturation * value the distance from
+// the axis, so dark colors are close together whatever their hue.
+float3 srgb_to_hsv(float3 c) {
+    const float v = max(c.x, max(c.y, c.z));
+    const float chroma = v - min(c.x, min(c.y, c.z));
+    float h = 0;
+    if (chroma > 0) {
+        if (v == c.x) {
+            h = (c.y - c.z) / chroma;
+        } else if (v == c.y) {
+            h = (c.z - c.x) / chroma + 2;
+        } else {
+            h = (c.x - c.y) / chroma + 4;
+        }
+    }
+    const float angle = h * M_PI_F / 3;
+    return (float3)(chroma * cos(angle), chroma * sin(angle), v);
+}
+
+float3 hsv_to_srgb(float3 cone) {
+    const float v = cone.z;
+    const float chroma = length(cone.xy);
+    float h = atan2(cone.y, cone.x) * 3 / M_PI_F;
+    if (h < 0) {
+        h += 6;
+    }
+    const float x = chroma * (1 - fabs(fmod(h, 2.0f) - 1));
+    float3 rgb;
+    if (h < 1) {
+        rgb = (float3)(chroma, x, 0);
+    } else if (h < 2) {
+        rgb = (float3)(x, chroma, 0);
+    } else if (h < 3) {
+        rgb = (float3)(0, chroma, x);
+    } else if (h < 4) {
+        rgb = (float3)(0, x, chroma);
+    } else if (h < 5) {
+        rgb = (float3)(x, 0, chroma);
+    } else {
+        rgb = (float3)(chroma, 0, x);
+    }
+    // More chroma than value comes out negative, so out of gamut.
+    return rgb + (v - chroma);
+}
+
+float3 to_color_space(float3 srgb, int space) {
+    switch (space) {
+    case COLOR_SPACE_LINEAR: return srgb_to_linear(srgb) / sqrt(3.0f);
+    case COLOR_SPACE_LAB: return linear_to_lab(srgb_to_linear(srgb));
+    case COLOR_SPACE_OKLAB: return linear_to_oklab(srgb_to_linear(srgb));
+    case COLOR_SPACE_HSV: return srgb_to_hsv(srgb);
+    default: return srgb;
+    }
+}
+
+// Can be out of gamut.
+float3 from_color_space(float3 c, int space) {
+    switch (space) {
+    case COLOR_SPACE_LINEAR: return linear_to_srgb(c * sqrt(3.0f));
+    case COLOR_SPACE_LAB: return linear_to_srgb(lab_to_linear(c));
+    case COLOR_SPACE_OKLAB: return linear_to_srgb(oklab_to_linear(c));
+    case COLOR_SPACE_HSV: return hsv_to_srgb(c);
+    default: return c;
+    }
+}
+
+// Some slack for rounding on the way through the other spaces.
+bool in_gamut(float3 srgb) {
+    const float slack = 1e-4f;
+    return all(srgb >= -slack) && all(srgb <= 1 + slack);
+}
+
 // How different are these colors?
 // Returns [0, 1] where 0 is most similar.
-float color_distance(float4 rgba1, float4 rgba2) {
-    // const float r = rgba1.x - rgba2.x;
-    // const float g = rgba1.y - rgba2.y;
-    // const float b = rgba1.z - rgba2.z;
-    // return (r * r + g * g + b * b) / 3;
-
-    const float r = fabs(rgba1.x - rgba2.x);
-    const float g = fabs(rgba1.y - rgba2.y);
-    const float b = fabs(rgba1.z - rgba2.z);
-    return (r + g + b ) / 3;
+// In srgb it's the mean channel difference, in the others the straight
+// line distance in their space.
+float color_distance(float4 rgba1, float4 rgba2, int space) {
+    if (space == COLOR_SPACE_SRGB) {
+        const float r = fabs(rgba1.x - rgba2.x);
+        const float g = fabs(rgba1.y - rgba2.y);
+        const float b = fabs(rgba1.z - rgba2.z);
+        return (r + g + b ) / 3;
+    }
+    return min(distance(to_color_space(rgba1.xyz, space), to_color_space(rgba2.xyz, space)), 1.0f);
 }
 
 // Score each location based on how close its neighbors are to the goal color.
-// Only score against pixels that are white on the mask.
+// Only score against pixels that are filled on the mask.
 __kernel void score(
     read_only image2d_t source,
     read_only image2d_t mask,
     float4 goal,
+    int boundary,
     write_only image2d_t dest)
 {
     const int2 pixel_id = (int2)(get_global_id(0), get_global_id(1));
@@ -135,16 +378,15 @@ __kernel void score(
     int n_scored_neighbors = 0;
     for (int dx = -1; dx <= 1;  dx++) {
         for (int dy = -1; dy <= 1;  dy++) {
-            const int2 loc = pixel_id + (int2)(dx, dy);
+            int2 loc = pixel_id + (int2)(dx, dy);
             bool self = (dx == 0 && dy == 0);
-            bool in_bounds = (loc.x >= 0 && loc.y >= 0 && loc.x < dims.x && loc.y < dims.y);
             // bool select = true;
-            if (!self && in_bounds) {
-                const float4 mask_neighbor = read_imagef(mask, sampler_const, loc);
-                if (mask_neighbor.x > .5) {
+            if (!self && boundary_loc(&loc, dims, boundary)) {
+                const uint4 mask_neighbor = read_imageui(mask, sampler_const, loc);
+                if (mask_neighbor.x > 0) {
                     const float4 rgba_neighbor = read_imagef(source, sampler_const, loc);
                     n_scored_neighbors += 1;
-                    const float neighbor_score = color_distance(goal, rgba_neighbor);
+                    const float neighbor_score = color_distance(goal, rgba_neighbor, COLOR_SPACE_SRGB);
                     acc += neighbor_score;
                     if (neighbor_score < min_neighbor_score) {
                         min_neighbor_score = neighbor_score;
@@ -170,58 +412,79 @@ __kernel void score(
     // }
 }
 
-// this one is really bad
-float rand_xorshift(uint2 randoms, uint globalID) {
-    // https://stackoverflow.com/questions/9912143/how-to-get-a-random-number-in-opencl
-    uint seed = randoms.x + globalID;
-    uint t = seed ^ (seed << 11);
-    uint result = randoms.y ^ (randoms.y >> 19) ^ (t ^ (t >> 8));
-    return (float)result / 4294967295.0;
-}
-
-// 1 <= *seed < m
-uint rand_pm_uint(uint *seed) {
-    // return (*seed) / 2147483647.0f; // 2**31-1
-
-    // https://stackoverflow.com/questions/9912143/how-to-get-a-random-number-in-opencl
-    long const a = 16807; // 7**5
-    long const m = 2147483647; // 2**31-1
-    *seed = ((long)(*seed) * a) % m;
-    // return (*seed) / 4294967295.0;
-    return *seed;
-}
-
-// 1 <= *seed < m
-float rand_pm(uint *seed) {
-    return rand_pm_uint(seed) / 2147483647.0f; // 2**31-1
-}
-
-// Find a new color that is different from `rgba1` by `d`.
-// d is [0, 1] where 0 is most similar.
-// TODO this is so wrong
-float4 color_at_distance(float4 rgba1, float d, uint *rand_seed) {
-    float3 delta = (float3)(rand_pm(rand_seed), rand_pm(rand_seed), rand_pm(rand_seed));
-    // delta = (float3)(.5,.5,.5);
-    // printf("%f %f %f\n", delta.x, delta.y, delta.z);
-    // float rx = rand_pm(rand_seed);
-    // float3 delta = (float3)(rx, rx, rx);
-    // return (float4)(delta.x, delta.x, delta.x, 1);
-
-    delta -= (float3)(.5, .5, .5);
-    // delta -= (float3)(.1, .1, .1);
-    // delta -= (float3)(.1, .1, .1);
-    // delta -= (float3)(.1, .1, .1);
-    // delta -= (float3)(.1, .1, .1);
-    // delta = (float3)(-10, -10, 2);
-    delta = normalize(delta) * d;
-    const float r = rgba1.x + delta.x;
-    const float g = rgba1.y + delta.y;
-    const float b = rgba1.z + delta.z;
-    // return (float4)(r, g, b, 1);
-    float4 result = (float4)(r, g, b, rgba1.w);
-    // printf("%f\n", rgba1.z);
-    // result = (float4)(0,0,0,1);
-    return clamp(result, 0.0f, 1.0f);
+// Random numbers are SplitMix64: the output is a strong hash of a counter,
+// so each pixel only needs to keep its counter around between frames.
+// http://prng.di.unimi.it/splitmix64.c
+// Keep in sync with cpu.rs.
+#define RAND_GAMMA 0x9E3779B97F4A7C15UL
+
+ulong rand_mix(ulong z) {
+    z = (z ^ (z >> 30)) * 0xBF58476D1CE4E5B9UL;
+    z = (z ^ (z >> 27)) * 0x94D049BB133111EBUL;
+    return z ^ (z >> 31);
+}
+
+uint rand_uint(ulong *state) {
+    *state += RAND_GAMMA;
+    return (uint)(rand_mix(*state) >> 32);
+}
+
+// [0, 1)
+float rand_float(ulong *state) {
+    return (rand_uint(state) >> 8) * (1.0f / 16777216.0f); // 2**24
+}
+
+// Give every pixel its own starting counter. Run once per run.
+__kernel void rand_seed(global ulong *rng_state, ulong seed) {
+    const uint rng_id = get_global_id(0) + get_global_id(1) * get_global_size(0);
+    rng_state[rng_id] = rand_mix(seed + (ulong)rng_id * RAND_GAMMA);
+}
+
+// A random direction, uniform over the sphere.
+// Points from the cube are kept only inside the ball, so corners aren't favored.
+float3 random_direction(ulong *rng) {
+    float3 v = (float3)(1, 0, 0);
+    for (int i = 0; i < 8; i++) {
+        const float rx = rand_float(rng);
+        const float ry = rand_float(rng);
+        const float rz = rand_float(rng);
+        v = (float3)(rx, ry, rz) - (float3)(.5, .5, .5);
+        const float len = length(v);
+        if (len > 1e-3f && len <= .5f) {
+            break;
+        }
+    }
+    return length(v) > 1e-3f ? normalize(v) : (float3)(1, 0, 0);
+}
+
+#define COLOR_STEP_TRIES 16
+
+// Find a new color that is `d` away from `rgba1` in `space`, or less when
+// boxed into a corner of the gamut. d is [0, 1] where 0 is most similar.
+// Directions that leave the gamut are drawn again rather than clamped, which
+// would shorten the step and pile colors up on the faces of the rgb cube.
+float4 color_at_distance(float4 rgba1, float d, ulong *rng, int space) {
+    const float3 from = to_color_space(rgba1.xyz, space);
+    float3 dir;
+    for (int i = 0; i < COLOR_STEP_TRIES; i++) {
+        dir = random_direction(rng);
+        const float3 srgb = from_color_space(from + dir * d, space);
+        if (in_gamut(srgb)) {
+            return (float4)(clamp(srgb, 0.0f, 1.0f), rgba1.w);
+        }
+    }
+    // Boxed into a corner. Go as far along the last direction as the gamut allows.
+    float lo = 0;
+    float hi = d;
+    for (int i = 0; i < 12; i++) {
+        const float mid = (lo + hi) / 2;
+        if (in_gamut(from_color_space(from + dir * mid, space))) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    return (float4)(clamp(from_color_space(from + dir * lo, space), 0.0f, 1.0f), rgba1.w);
 }
 
 float4 color_avg(float4 rgba1, float4 rgba2) {
@@ -245,17 +508,21 @@ __constant int2 neighbor_deltas[8] = {
     (int2)(1, 1),
 };
 
-// Pick a new color for pixels on the frontier.
-// Mask determines which frontier a pixel is part of. 0 means not started. 1 mean first generation. Etc.
-__kernel void pastiche(
+// The pastiche kernel for one pixel. Split out so the kernel can load and
+// store the pixel's rng state around it no matter where this returns.
+void pastiche_pixel(
     read_only image2d_t in_canvas,
     read_only image2d_t in_mask,
     read_only image2d_t in_subject,
-    global uint *rand,
-    read_only uint time_ms,
-    read_only uint cursor_enabled,
-    read_only uint cursor_pressed,
-    read_only uint2 cursor_xy,
+    read_only image2d_t in_stencil,
+    ulong *rng,
+    uint time_ms,
+    uint cursor_enabled,
+    uint cursor_pressed,
+    uint2 cursor_xy,
+    int color_space,
+    float color_distance,
+    int boundary,
     write_only image2d_t out_canvas,
     write_only image2d_t out_mask)
 {
@@ -263,11 +530,6 @@ __kernel void pastiche(
     const int2 dims = get_image_dim(out_canvas);
     // const float2 pixel_idf  = (float2)((float)pixel_id.x, (float)pixel_id.y);
 
-    // per-pixel rand
-    const uint rand_id = get_global_id(0) + get_global_id(1) * get_global_size(0);
-    uint rand_seed = rand[rand_id]; // per-pixel rand
-    uint rand_seed_frame = rand[0]; // per-frame rand
-
     // Show the subject
     // const float4 subject_rgba = read_imagef(in_subject, sampler_const, pixel_id);
     // write_imagef(out_canvas, pixel_id, subject_rgba);
@@ -275,8 +537,8 @@ __kernel void pastiche(
 
     // // test of randomness
     // {
-    //     float rx1 = rand_pm(&rand_seed);
-    //     float rx2 = rand_pm(&rand_seed);
+    //     float rx1 = rand_float(rng);
+    //     float rx2 = rand_float(rng);
     //     /\* if (pixel_id == (int2)(100, 100)) { *\/
     //     if (pixel_id.x == 100 && pixel_id.y == 100) {
     //         printf("%f, %f\n", rx1, rx2);
@@ -288,8 +550,8 @@ __kernel void pastiche(
     // }
 
     // Clear some pixels sometimes
-    // if (rand_pm(&rand_seed) < 0.1) {
-    // // if (rand_pm(&rand_seed) + (convert_float(pixel_id.x) / convert_float(dims.x))*.1 > 1) {
+    // if (rand_float(rng) < 0.1) {
+    // // if (rand_float(rng) + (convert_float(pixel_id.x) / convert_float(dims.x))*.1 > 1) {
     //     const float4 src_rgba = read_imagef(in_canvas, sampler_const, pixel_id);
     //     float4 out_mask_rgba = (float4)(0, 0, 0, 1);
     //     write_imagef(out_canvas, pixel_id, src_rgba);
@@ -299,7 +561,7 @@ __kernel void pastiche(
 
     // // Refresh pixels from circles
     // for (int i = 0; i < 1; i++) {
-    //     const float2 refresher = (float2)(rand_pm(&rand_seed_frame) * dims.x, rand_pm(&rand_seed_frame) * dims.y);
+    //     const float2 refresher = (float2)(rand_float(rng) * dims.x, rand_float(rng) * dims.y);
     //     if (distance(convert_float2(pixel_id), refresher) <= 50) {
     //         float4 out_mask_rgba = (float4)(0, 0, 0, 1);
     //         // write_imagef(out_canvas, pixel_id, (float4)(1, 1, 1, 1));
@@ -311,9 +573,9 @@ __kernel void pastiche(
     // Cursor blockout
     // if (cursor_enabled > 0 && time_ms > 4000) {
     //     const float distance_to_cursor = distance(convert_float2(pixel_id), convert_float2(cursor_xy));
-    //     if (distance_to_cursor < 40 + (20 * rand_pm(&rand_seed))) {
-    //         /\* const int2 offset = (int2)((rand_pm(&rand_seed) - 0.5) * 3 + 4, *\/
-    //         /\*                         (rand_pm(&rand_seed) - 0.5) * 2 + 2); *\/
+    //     if (distance_to_cursor < 40 + (20 * rand_float(rng))) {
+    //         /\* const int2 offset = (int2)((rand_float(rng) - 0.5) * 3 + 4, *\/
+    //         /\*                         (rand_float(rng) - 0.5) * 2 + 2); *\/
     //         const int2 offset = (int2)(0,0);
     //         const float4 src_rgba = read_imagef(in_canvas, sampler_const, pixel_id + offset);
     //         /\* const float4 out_canvas_rgba = (float4)(0, 0, 0, 1); *\/
@@ -326,7 +588,7 @@ __kernel void pastiche(
 
     // Cursor pressed
     if (cursor_enabled > 0 && cursor_pressed > 0) {
-        const float distance_to_cursor = distance(convert_float2(pixel_id), convert_float2(cursor_xy));
+        const float distance_to_cursor = boundary_distance(pixel_id, convert_int2(cursor_xy), dims, boundary);
         if (time_ms < 30000) {
             if (distance_to_cursor < 20) {
                 const float4 out_canvas_rgba = (float4)(0.667, 0, 0, 1);
@@ -351,12 +613,12 @@ __kernel void pastiche(
     }
 
     // Do the fizzy thing where pixels wiggle around.
-    // if (rand_pm(&rand_seed) < 0.05) {
+    // if (rand_float(rng) < 0.05) {
     //     /\* float2 virtual_xy = (float2)(pixel_idf.x / 50, pixel_idf.y / 50); *\/
     //     /\* const int2 center_xy = (int2)(dims.x / 2, dims.y / 2); *\/
     //     /\* float factor = distance(convert_float2(pixel_id), convert_float2(center_xy)) / distance((float2)(0, 0), convert_float2(dims)); *\/
-    //     const int2 offset = (int2)((rand_pm(&rand_seed) - 0.5) * 3,
-    //                                (rand_pm(&rand_seed) - 0.5) * 2);
+    //     const int2 offset = (int2)((rand_float(rng) - 0.5) * 3,
+    //                                (rand_float(rng) - 0.5) * 2);
     //     const float4 src_rgba = read_imagef(in_canvas, sampler_const, pixel_id + offset);
     //     const float4 mask_self = read_imagef(in_mask, sampler_const, pixel_id + offset);
     //     write_imagef(out_canvas, pixel_id, src_rgba);
@@ -368,20 +630,22 @@ __kernel void pastiche(
     const uint4 mask_self = read_imageui(in_mask, sampler_const, pixel_id);
 
     // Slow it all down. Causes growth in a fuzzy circle rather than a strict square.
-    if (rand_pm(&rand_seed) < 0.55) {
+    // Pixels that don't change still have to be written or the output holds garbage.
+    if (rand_float(rng) < 0.55) {
+        write_imagef(out_canvas, pixel_id, src_rgba);
+        write_imageui(out_mask, pixel_id, mask_self);
         return;
     }
 
     uint max_neighbor_mask = 0;
     float4 selected_neighbor_rgba = (float4)(1,0,1,1);
-    int neighbor_index_offset = rand_pm_uint(&rand_seed) % 8;
+    int neighbor_index_offset = rand_uint(rng) % 8;
     // int2 neighbors_dxy = (int2)(0, 0);
     for (int i = 0; i < 8; i++) {
         int2 dxy = neighbor_deltas[(i + neighbor_index_offset) % 8];
-        const int2 loc = pixel_id + dxy;
-        bool in_bounds = (loc.x >= 0 && loc.y >= 0 && loc.x < dims.x && loc.y < dims.y);
-        if (in_bounds) {
-            const int4 mask_neighbor = read_imagei(in_mask, sampler_const, loc);
+        int2 loc = pixel_id + dxy;
+        if (boundary_loc(&loc, dims, boundary)) {
+            const uint4 mask_neighbor = read_imageui(in_mask, sampler_const, loc);
             if (mask_neighbor.x > max_neighbor_mask) {
                 max_neighbor_mask = mask_neighbor.x;
                 const float4 rgba_neighbor = read_imagef(in_canvas, sampler_const, loc);
@@ -393,10 +657,23 @@ __kernel void pastiche(
 
     if (max_neighbor_mask == 0) {
         // No interesting neighbors.
+        write_imagef(out_canvas, pixel_id, src_rgba);
+        write_imageui(out_mask, pixel_id, mask_self);
         return;
     }
     if (mask_self.x >= max_neighbor_mask) {
         // This spot is filled with the latest nearby generation already.
+        write_imagef(out_canvas, pixel_id, src_rgba);
+        write_imageui(out_mask, pixel_id, mask_self);
+        return;
+    }
+
+    // Walled off by the stencil, or not this time. Only partly open pixels draw,
+    // so without a stencil the draws are the same as ever.
+    const float open = read_imagef(in_stencil, sampler_const, pixel_id).x;
+    if (open < 1 && (open <= 0 || rand_float(rng) >= open)) {
+        write_imagef(out_canvas, pixel_id, src_rgba);
+        write_imageui(out_mask, pixel_id, mask_self);
         return;
     }
 
@@ -414,8 +691,8 @@ __kernel void pastiche(
         // out_canvas_rgba = (float4)(0, 1, .2, 1);
         // out_canvas_rgba = selected_neighbor_rgba;
         // TODO distance should be selected randomly (along a curve representative of original)
-        // const float distance = rand_pm(&rand_seed);
-        // out_canvas_rgba = (float4)(0, .5, rand_pm(&rand_seed), 1);
+        // const float distance = rand_float(rng);
+        // out_canvas_rgba = (float4)(0, .5, rand_float(rng), 1);
 
         // const float4 subject_rgba = read_imagef(in_subject, sampler_const, pixel_id);
 
@@ -424,8 +701,7 @@ __kernel void pastiche(
         // const float distance = .005;
         // const float distance = cos(convert_float(time_ms) * 0.0001) * .08f;
         // const float distance = cos(convert_float(pixel_id.x) * 0.004) * .08f;
-        const float color_distance = .06f;;
-        out_canvas_rgba = color_at_distance(selected_neighbor_rgba, color_distance, &rand_seed);
+        out_canvas_rgba = color_at_distance(selected_neighbor_rgba, color_distance, rng, color_space);
         // const float factor = 0.02 + 0.02 * -cos(convert_float(time_ms / 3000));
         // const float factor = 0.1 * (1.0f - length(subject_rgba) / 3);
         // float max4len = length((float4)(1, 1, 1, 1));
@@ -436,16 +712,16 @@ __kernel void pastiche(
         // float factor = 0.04f;
         // float factor = 0.1f;
         // const float4 departure_lounge = color_mix(factor, selected_neighbor_rgba, subject_rgba);
-        // out_canvas_rgba = color_at_distance(departure_lounge, distance, &rand_seed);
+        // out_canvas_rgba = color_at_distance(departure_lounge, distance, rng);
 
         // Write out the subject.
         // const int grid_density = 50 * sin(convert_float(pixel_id.x)/100.f) * sin(convert_float(time_ms)/2000.f);
-        // if ((rand_pm(&rand_seed) < 0.9) && (pixel_id.x % grid_density == 0 || pixel_id.y % grid_density == 0)) {
+        // if ((rand_float(rng) < 0.9) && (pixel_id.x % grid_density == 0 || pixel_id.y % grid_density == 0)) {
         float subject_chance = 0.06;
-        const float distance_to_cursor = distance(convert_float2(pixel_id), convert_float2(cursor_xy));
+        const float distance_to_cursor = boundary_distance(pixel_id, convert_int2(cursor_xy), dims, boundary);
         const float distance_to_cursor_normed = distance_to_cursor / distance((float2)(0.f, 0.f), convert_float2(dims));
         subject_chance = 0.3 - (distance_to_cursor_normed*1);
-        if (rand_pm(&rand_seed) < subject_chance) {
+        if (rand_float(rng) < subject_chance) {
             const float4 subject_rgba = read_imagef(in_subject, sampler_const, pixel_id);
             out_canvas_rgba = subject_rgba;
         }
@@ -454,18 +730,18 @@ __kernel void pastiche(
         // for (int i = 0; i < 100; i++) {
         //     if (color_distance(subject_rgba, out_canvas_rgba) > 0.1) {
         // 	// Re-reoll
-        // 	out_canvas_rgba = color_at_distance(selected_neighbor_rgba, distance, &rand_seed);
+        // 	out_canvas_rgba = color_at_distance(selected_neighbor_rgba, distance, rng);
         //     } else {
         //       break;
         //     }
         // }
 
-        // const float rx = rand_pm(&rand_seed);
+        // const float rx = rand_float(rng);
         // out_canvas_rgba = (float4)(rx, rx, rx, 1);
 
-        // const float xjdf = rand_pm(&rand_seed);
+        // const float xjdf = rand_float(rng);
         // out_canvas_rgba = (float4)(xjdf, xjdf, xjdf, 1.0);
-        // out_canvas_rgba = (float4)(rand_pm(&rand_seed), rand_pm(&rand_seed), rand_pm(&rand_seed), 1);
+        // out_canvas_rgba = (float4)(rand_float(rng), rand_float(rng), rand_float(rng), 1);
         out_mask_rgba = (uint4)(max_neighbor_mask, max_neighbor_mask, max_neighbor_mask, max_neighbor_mask);
     }
     // if (mask_self.x == 0) {
@@ -482,3 +758,243 @@ __kernel void pastiche(
     write_imagef(out_canvas, pixel_id, out_canvas_rgba);
     write_imageui(out_mask, pixel_id, out_mask_rgba);
 }
+
+// Pick a new color for pixels on the frontier.
+// Mask determines which frontier a pixel is part of. 0 means not started. 1 mean first generation. Etc.
+// Stencil is how likely each pixel is to fill when it can, 0 to never grow there.
+__kernel void pastiche(
+    read_only image2d_t in_canvas,
+    read_only image2d_t in_mask,
+    read_only image2d_t in_subject,
+    read_only image2d_t in_stencil,
+    global ulong *rng_state,
+    read_only uint time_ms,
+    read_only uint cursor_enabled,
+    read_only uint cursor_pressed,
+    read_only uint2 cursor_xy,
+    int color_space,
+    float color_distance,
+    int boundary,
+    write_only image2d_t out_canvas,
+    write_only image2d_t out_mask)
+{
+    // per-pixel rand
+    const uint rng_id = get_global_id(0) + get_global_id(1) * get_global_size(0);
+    ulong rng = rng_state[rng_id];
+    pastiche_pixel(in_canvas, in_mask, in_subject, in_stencil, &rng,
+                   time_ms, cursor_enabled, cursor_pressed, cursor_xy, color_space, color_distance, boundary,
+                   out_canvas, out_mask);
+    rng_state[rng_id] = rng;
+}
+
+// fejesjoco's "all colors": place every color of a set exactly once, each at
+// the frontier pixel whose filled neighbors are closest to it.
+// https://codegolf.stackexchange.com/questions/22144/images-with-all-colors
+//
+// Everything stays on the device so the host only enqueues. For each color:
+//   allcolors_score scores the frontier and leaves one best per work group,
+//   allcolors_reduce picks the best of those,
+//   allcolors_place paints it and updates the frontier.
+//
+// Canvas and colors are rgba bytes, mask is one uint generation per pixel (0 = empty).
+// The frontier doesn't grow onto pixels where the stencil byte is 0.
+// The frontier is a compact list of pixel indices (y * width + x) with
+// frontier_slot as the reverse lookup (UINT_MAX when not on the frontier).
+// Keep in sync with allcolors.rs.
+
+#define ALLCOLORS_GROUP 256
+
+// Layout of the `state` buffer.
+#define ALLCOLORS_COUNT 0 // number of pixels on the frontier
+#define ALLCOLORS_NEXT 1 // index of the next color to place
+#define ALLCOLORS_BEST 2 // frontier slot picked by allcolors_reduce
+
+// Lower scores win. Ties go to the lower slot so the result doesn't depend on scheduling.
+bool allcolors_better(float score1, uint slot1, float score2, uint slot2) {
+    return score1 < score2 || (score1 == score2 && slot1 < slot2);
+}
+
+// Leave the best of the work group in element 0.
+void allcolors_argmin_local(local float *scores, local uint *slots) {
+    const uint lid = get_local_id(0);
+    for (uint stride = ALLCOLORS_GROUP / 2; stride > 0; stride /= 2) {
+        barrier(CLK_LOCAL_MEM_FENCE);
+        if (lid < stride && allcolors_better(scores[lid + stride], slots[lid + stride], scores[lid], slots[lid])) {
+            scores[lid] = scores[lid + stride];
+            slots[lid] = slots[lid + stride];
+        }
+    }
+    barrier(CLK_LOCAL_MEM_FENCE);
+}
+
+// Distance from the goal to the closest filled neighbor.
+float allcolors_pixel_score(
+    global const uchar *canvas,
+    global const uint *mask,
+    uint p, uint width, uint height,
+    float4 goal, int color_space, int boundary)
+{
+    const int2 dims = (int2)(width, height);
+    const int2 pixel_id = (int2)(p % width, p / width);
+    float score = INFINITY;
+    for (int i = 0; i < 8; i++) {
+        int2 loc = pixel_id + neighbor_deltas[i];
+        if (boundary_loc(&loc, dims, boundary)) {
+            const uint q = loc.y * width + loc.x;
+            if (mask[q] != 0) {
+                const float4 rgba_neighbor = convert_float4(vload4(q, canvas)) / 255.0f;
+                score = min(score, color_distance(goal, rgba_neighbor, color_space));
+            }
+        }
+    }
+    return score;
+}
+
+// Score the frontier against the next color.
+// Each work item strides over the frontier, then each group reduces to one candidate.
+__kernel __attribute__((reqd_work_group_size(ALLCOLORS_GROUP, 1, 1)))
+void allcolors_score(
+    global const uchar *canvas,
+    global const uint *mask,
+    global const uint *frontier,
+    global const uint *state,
+    global const uchar *colors,
+    uint ncolors,
+    uint width,
+    uint height,
+    int color_space,
+    int boundary,
+    global float *partial_score,
+    global uint *partial_slot)
+{
+    local float scores[ALLCOLORS_GROUP];
+    local uint slots[ALLCOLORS_GROUP];
+
+    const uint count = state[ALLCOLORS_COUNT];
+    const uint next = state[ALLCOLORS_NEXT];
+    float best_score = INFINITY;
+    uint best_slot = UINT_MAX;
+    if (next < ncolors) {
+        const float4 goal = convert_float4(vload4(next, colors)) / 255.0f;
+        for (uint slot = get_global_id(0); slot < count; slot += get_global_size(0)) {
+            const float score = allcolors_pixel_score(canvas, mask, frontier[slot], width, height, goal, color_space, boundary);
+            if (allcolors_better(score, slot, best_score, best_slot)) {
+                best_score = score;
+                best_slot = slot;
+            }
+        }
+    }
+
+    scores[get_local_id(0)] = best_score;
+    slots[get_local_id(0)] = best_slot;
+    allcolors_argmin_local(scores, slots);
+    if (get_local_id(0) == 0) {
+        partial_score[get_group_id(0)] = scores[0];
+        partial_slot[get_group_id(0)] = slots[0];
+    }
+}
+
+// Reduce the per-group candidates to one. Run as a single work group.
+__kernel __attribute__((reqd_work_group_size(ALLCOLORS_GROUP, 1, 1)))
+void allcolors_reduce(
+    global const float *partial_score,
+    global const uint *partial_slot,
+    uint npartial,
+    global uint *state)
+{
+    local float scores[ALLCOLORS_GROUP];
+    local uint slots[ALLCOLORS_GROUP];
+
+    float best_score = INFINITY;
+    uint best_slot = UINT_MAX;
+    for (uint i = get_local_id(0); i < npartial; i += ALLCOLORS_GROUP) {
+        if (allcolors_better(partial_score[i], partial_slot[i], best_score, best_slot)) {
+            best_score = partial_score[i];
+            best_slot = partial_slot[i];
+        }
+    }
+
+    scores[get_local_id(0)] = best_score;
+    slots[get_local_id(0)] = best_slot;
+    allcolors_argmin_local(scores, slots);
+    if (get_local_id(0) == 0) {
+        state[ALLCOLORS_BEST] = slots[0];
+    }
+}
+
+void allcolors_frontier_remove(
+    global uint *frontier,
+    global uint *frontier_slot,
+    global uint *state,
+    uint p)
+{
+    const uint slot = frontier_slot[p];
+    if (slot == UINT_MAX) {
+        return;
+    }
+    // Move the last entry into the hole.
+    const uint last = state[ALLCOLORS_COUNT] - 1;
+    const uint moved = frontier[last];
+    frontier[slot] = moved;
+    frontier_slot[moved] = slot;
+    frontier_slot[p] = UINT_MAX;
+    state[ALLCOLORS_COUNT] = last;
+}
+
+// Paint the next color and grow the frontier around it. Run as a single work item.
+// Goes where allcolors_reduce picked, or at `at` when at.x >= 0 (for seeds).
+__kernel void allcolors_place(
+    global uchar *canvas,
+    global uint *mask,
+    global const uchar *stencil,
+    global uint *frontier,
+    global uint *frontier_slot,
+    global uint *state,
+    global const uchar *colors,
+    uint ncolors,
+    uint width,
+    uint height,
+    int boundary,
+    int2 at,
+    uint generation)
+{
+    const uint next = state[ALLCOLORS_NEXT];
+    if (next >= ncolors) {
+        // Out of colors.
+        return;
+    }
+
+    uint p;
+    if (at.x >= 0) {
+        p = at.y * width + at.x;
+        if (mask[p] != 0) {
+            return;
+        }
+    } else {
+        const uint slot = state[ALLCOLORS_BEST];
+        if (slot == UINT_MAX) {
+            // Nowhere to go.
+            return;
+        }
+        p = frontier[slot];
+    }
+
+    vstore4(vload4(next, colors), p, canvas);
+    mask[p] = max(generation, 1u);
+    state[ALLCOLORS_NEXT] = next + 1;
+    allcolors_frontier_remove(frontier, frontier_slot, state, p);
+
+    const int2 pixel_id = (int2)(p % width, p / width);
+    for (int i = 0; i < 8; i++) {
+        int2 loc = pixel_id + neighbor_deltas[i];
+        if (boundary_loc(&loc, (int2)(width, height), boundary)) {
+            const uint q = loc.y * width + loc.x;
+            if (mask[q] == 0 && stencil[q] != 0 && frontier_slot[q] == UINT_MAX) {
+                const uint count = state[ALLCOLORS_COUNT];
+                frontier[count] = q;
+                frontier_slot[q] = count;
+                state[ALLCOLORS_COUNT] = count + 1;
+            }
+        }
+    }
+}
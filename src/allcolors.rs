@@ -0,0 +1,338 @@
+use image;
+use ocl;
+use ocl::{Context, Device, Platform, Queue, Program, Kernel, Buffer};
+use rand::seq::SliceRandom;
+use backend::{Backend, Canvas, Mask, MaskVal, StepParams};
+use checkpoint;
+use config::{Boundary, ColorSpace, Config};
+use gpu;
+use sim;
+use stencil;
+use stencil::Stencil;
+
+// fejesjoco's "all colors" on the gpu, see the allcolors kernels in main.cl.
+// Every color of a generated set gets placed exactly once, at the frontier
+// pixel whose filled neighbors are closest to it.
+// The subject and cursor are ignored. The canvas stays 8 bit, since the
+// colors are placed exactly as generated and never mixed.
+
+// Keep in sync with main.cl.
+const GROUP: usize = 256;
+const STATE_COUNT: usize = 0;
+const STATE_NEXT: usize = 1;
+const STATE_LEN: usize = 3;
+const NONE: u32 = ::std::u32::MAX;
+
+// Work groups for scoring. Each work item strides over the frontier,
+// so this only needs to be enough to fill the device.
+const SCORE_GROUPS: usize = 256;
+
+// The color shuffle's stream, see `sim::frame_rng`.
+const COLORS_STREAM: u64 = ::std::u64::MAX;
+
+// One pixel for each 24 bit color.
+pub const MAX_PIXELS: u64 = 1 << 24;
+
+// `n` distinct colors evenly spread over the rgb cube, shuffled.
+// Uses the fewest levels per channel that give enough colors,
+// so 4096x4096 gets every 24 bit color.
+pub fn color_set(n: usize, seed: u64) -> Vec<u8> {
+    let mut levels: usize = 1;
+    while levels * levels * levels < n {
+        levels += 1;
+    }
+    if n as u64 > MAX_PIXELS {
+        panic!("allcolors needs at most 2^24 pixels, got {}", n);
+    }
+    let level = |i: usize| -> u8 {
+        if levels == 1 { 0 } else { (i * 255 / (levels - 1)) as u8 }
+    };
+    let mut colors: Vec<[u8; 4]> = Vec::with_capacity(levels * levels * levels);
+    for r in 0..levels {
+        for g in 0..levels {
+            for b in 0..levels {
+                colors.push([level(r), level(g), level(b), 255]);
+            }
+        }
+    }
+    colors.shuffle(&mut sim::frame_rng(seed, COLORS_STREAM));
+    colors.truncate(n);
+    colors.iter().flat_map(|c| c.iter().cloned()).collect()
+}
+
+// Device state. Everything the kernels work on.
+struct Buffers {
+    canvas: Buffer<u8>,
+    mask: Buffer<MaskVal>,
+    // Zero where the frontier can't go, see stencil.rs.
+    stencil: Buffer<u8>,
+    colors: Buffer<u8>,
+    frontier: Buffer<u32>,
+    frontier_slot: Buffer<u32>,
+    state: Buffer<u32>,
+    partial_score: Buffer<f32>,
+    partial_slot: Buffer<u32>,
+}
+
+struct Kernels {
+    score: Kernel,
+    reduce: Kernel,
+    place: Kernel,
+    // Same as place but at a given position, for seeds.
+    // `place_pixel` sets its `at` and `generation` before every call.
+    place_at: Kernel,
+}
+
+fn build_kernels(program: &Program, queue: &Queue, cl: &Buffers, dims: (u32, u32),
+                 color_space: ColorSpace, boundary: Boundary) -> Result<Kernels, String> {
+    let color_space = ocl::prm::Int::new(color_space as i32);
+    let boundary = ocl::prm::Int::new(boundary as i32);
+    let ncolors = ocl::prm::Uint::new(dims.0 * dims.1);
+    let width = ocl::prm::Uint::new(dims.0);
+    let height = ocl::prm::Uint::new(dims.1);
+    let err = |err: ocl::Error| format!("{}", err);
+
+    let score = Kernel::builder()
+        .name("allcolors_score")
+        .program(program)
+        .queue(queue.clone())
+        .global_work_size(SCORE_GROUPS * GROUP)
+        .local_work_size(GROUP)
+        .arg_named("canvas", Some(&cl.canvas))
+        .arg_named("mask", Some(&cl.mask))
+        .arg_named("frontier", Some(&cl.frontier))
+        .arg_named("state", Some(&cl.state))
+        .arg_named("colors", Some(&cl.colors))
+        .arg_vec_named("ncolors", ncolors)
+        .arg_vec_named("width", width)
+        .arg_vec_named("height", height)
+        .arg_vec_named("color_space", color_space)
+        .arg_vec_named("boundary", boundary)
+        .arg_named("partial_score", Some(&cl.partial_score))
+        .arg_named("partial_slot", Some(&cl.partial_slot))
+        .build().map_err(err)?;
+
+    let reduce = Kernel::builder()
+        .name("allcolors_reduce")
+        .program(program)
+        .queue(queue.clone())
+        .global_work_size(GROUP)
+        .local_work_size(GROUP)
+        .arg_named("partial_score", Some(&cl.partial_score))
+        .arg_named("partial_slot", Some(&cl.partial_slot))
+        .arg_vec_named("npartial", ocl::prm::Uint::new(SCORE_GROUPS as u32))
+        .arg_named("state", Some(&cl.state))
+        .build().map_err(err)?;
+
+    let place_kernel_at = |at: ocl::prm::Int2| {
+        Kernel::builder()
+            .name("allcolors_place")
+            .program(program)
+            .queue(queue.clone())
+            .global_work_size(1)
+            .arg_named("canvas", Some(&cl.canvas))
+            .arg_named("mask", Some(&cl.mask))
+            .arg_named("stencil", Some(&cl.stencil))
+            .arg_named("frontier", Some(&cl.frontier))
+            .arg_named("frontier_slot", Some(&cl.frontier_slot))
+            .arg_named("state", Some(&cl.state))
+            .arg_named("colors", Some(&cl.colors))
+            .arg_vec_named("ncolors", ncolors)
+            .arg_vec_named("width", width)
+            .arg_vec_named("height", height)
+            .arg_vec_named("boundary", boundary)
+            .arg_vec_named("at", at)
+            .arg_vec_named("generation", ocl::prm::Uint::new(1))
+            .build().map_err(err)
+    };
+
+    Ok(Kernels {
+        score: score,
+        reduce: reduce,
+        place: place_kernel_at(ocl::prm::Int2::new(-1, -1))?,
+        place_at: place_kernel_at(ocl::prm::Int2::new(0, 0))?,
+    })
+}
+
+pub struct AllColorsBackend {
+    platform: Platform,
+    device: Device,
+    context: Context,
+    queue: Queue,
+    dims: (u32, u32),
+    color_space: ColorSpace,
+    boundary: Boundary,
+    cl: Buffers,
+    kernels: Kernels,
+    // Of the source `kernels` were built from.
+    source_crc: u32,
+    colors_per_step: u32,
+    steps: u64,
+}
+
+impl AllColorsBackend {
+    pub fn new(config: &Config, canvas: Canvas) -> AllColorsBackend {
+        let dims = config.dims;
+        let npixels = (dims.0 * dims.1) as usize;
+        let (platform, device, context, queue, program, source_crc) = gpu::open_program(config, "main.cl");
+
+        printlnc!(white_bold: "generating {} colors", npixels);
+        let colors = color_set(npixels, config.seed);
+
+        let buffer_u8 = |host: &[u8]| -> Buffer<u8> {
+            Buffer::<u8>::builder()
+                .queue(queue.clone())
+                .flags(ocl::flags::MEM_READ_WRITE)
+                .len(host.len())
+                .copy_host_slice(host)
+                .build().unwrap()
+        };
+        let buffer_u32 = |host: &[u32]| -> Buffer<u32> {
+            Buffer::<u32>::builder()
+                .queue(queue.clone())
+                .flags(ocl::flags::MEM_READ_WRITE)
+                .len(host.len())
+                .copy_host_slice(host)
+                .build().unwrap()
+        };
+
+        let cl = Buffers {
+            canvas: buffer_u8(&canvas),
+            // Start empty. Seeds come in through place_pixel so they join the frontier.
+            mask: buffer_u32(&vec![0; npixels]),
+            stencil: buffer_u8(&stencil::open(dims)),
+            colors: buffer_u8(&colors),
+            frontier: buffer_u32(&vec![0; npixels]),
+            frontier_slot: buffer_u32(&vec![NONE; npixels]),
+            state: buffer_u32(&vec![0; STATE_LEN]),
+            partial_score: Buffer::<f32>::builder()
+                .queue(queue.clone())
+                .flags(ocl::flags::MEM_READ_WRITE | ocl::flags::MEM_HOST_NO_ACCESS)
+                .len(SCORE_GROUPS)
+                .build().unwrap(),
+            partial_slot: buffer_u32(&vec![NONE; SCORE_GROUPS]),
+        };
+
+        let kernels = build_kernels(&program, &queue, &cl, dims, config.color_space, config.boundary)
+            .unwrap_or_else(|err| panic!("allcolors kernels: {}", err));
+
+        AllColorsBackend {
+            platform: platform,
+            device: device,
+            context: context,
+            queue: queue,
+            dims: dims,
+            color_space: config.color_space,
+            boundary: config.boundary,
+            cl: cl,
+            kernels: kernels,
+            source_crc: source_crc,
+            colors_per_step: config.colors_per_frame,
+            steps: 0,
+        }
+    }
+
+    // (frontier size, colors placed so far)
+    fn progress(&self) -> (u32, u32) {
+        let mut state = vec![0; STATE_LEN];
+        self.cl.state.read(&mut state).enq().unwrap();
+        (state[STATE_COUNT], state[STATE_NEXT])
+    }
+}
+
+impl Backend for AllColorsBackend {
+    fn describe(&self) -> String {
+        format!("allcolors on {}", gpu::describe_device(&self.platform, &self.device))
+    }
+
+    fn set_subject(&mut self, _subject: &Canvas) {}
+
+    // Only walls, the frontier can't take chances.
+    fn set_stencil(&mut self, stencil: &Stencil) {
+        self.cl.stencil.write(&stencil[..]).enq().unwrap();
+    }
+
+    fn reload(&mut self) -> Result<(), String> {
+        let (program, source_crc) = gpu::build_program(&self.context, self.device, "main.cl")?;
+        self.kernels = build_kernels(&program, &self.queue, &self.cl, self.dims, self.color_space, self.boundary)?;
+        self.source_crc = source_crc;
+        Ok(())
+    }
+
+    fn source_crc(&self) -> Option<u32> {
+        Some(self.source_crc)
+    }
+
+    // Seed the frontier at (x, y). The pixel gets the next color from the set
+    // rather than `color` so each color is still used exactly once.
+    fn place_pixel(&mut self, x: u32, y: u32, _color: image::Rgba<u8>, mask_value: MaskVal) {
+        self.kernels.place_at.set_arg_vec_named("at", ocl::prm::Int2::new(x as i32, y as i32)).unwrap();
+        self.kernels.place_at.set_arg_vec_named("generation", ocl::prm::Uint::new(mask_value)).unwrap();
+        unsafe{ self.kernels.place_at.enq().unwrap() };
+    }
+
+    fn step(&mut self, _params: &StepParams) {
+        for _ in 0..self.colors_per_step {
+            unsafe {
+                self.kernels.score.enq().unwrap();
+                self.kernels.reduce.enq().unwrap();
+                self.kernels.place.enq().unwrap();
+            }
+        }
+        // Don't let the host run ahead and queue up more than a frame.
+        self.queue.finish().unwrap();
+
+        if self.steps % 200 == 0 {
+            let (frontier, placed) = self.progress();
+            printlnc!(royal_blue: "allcolors: {} placed, {} on the frontier", placed, frontier);
+        }
+        self.steps += 1;
+    }
+
+    fn read_canvas(&mut self, canvas: &mut Canvas) {
+        let buf: &mut [u8] = canvas;
+        self.cl.canvas.read(buf).enq().unwrap();
+    }
+
+    fn read_mask(&mut self, mask: &mut Mask) {
+        let buf: &mut [MaskVal] = mask;
+        self.cl.mask.read(buf).enq().unwrap();
+    }
+
+    // The frontier and placement counters. The color set comes back from the seed.
+    fn read_state(&mut self) -> Vec<u8> {
+        let npixels = (self.dims.0 * self.dims.1) as usize;
+        let mut mask = vec![0; npixels];
+        let mut frontier = vec![0; npixels];
+        let mut frontier_slot = vec![0; npixels];
+        let mut state = vec![0; STATE_LEN];
+        self.cl.mask.read(&mut mask).enq().unwrap();
+        self.cl.frontier.read(&mut frontier).enq().unwrap();
+        self.cl.frontier_slot.read(&mut frontier_slot).enq().unwrap();
+        self.cl.state.read(&mut state).enq().unwrap();
+
+        let mut bytes = self.steps.to_le_bytes().to_vec();
+        bytes.extend(checkpoint::u32s_to_bytes(&mask));
+        bytes.extend(checkpoint::u32s_to_bytes(&frontier));
+        bytes.extend(checkpoint::u32s_to_bytes(&frontier_slot));
+        bytes.extend(checkpoint::u32s_to_bytes(&state));
+        bytes
+    }
+
+    fn write_state(&mut self, state: &[u8]) {
+        let npixels = (self.dims.0 * self.dims.1) as usize;
+        if state.len() != 8 + npixels * 12 + STATE_LEN * 4 {
+            panic!("allcolors checkpoint state doesn't match {}x{}", self.dims.0, self.dims.1);
+        }
+        let mut steps = [0; 8];
+        steps.copy_from_slice(&state[..8]);
+        self.steps = u64::from_le_bytes(steps);
+        let (mask, rest) = state[8..].split_at(npixels * 4);
+        let (frontier, rest) = rest.split_at(npixels * 4);
+        let (frontier_slot, rest) = rest.split_at(npixels * 4);
+        self.cl.mask.write(&checkpoint::u32s_from_bytes(mask)[..]).enq().unwrap();
+        self.cl.frontier.write(&checkpoint::u32s_from_bytes(frontier)[..]).enq().unwrap();
+        self.cl.frontier_slot.write(&checkpoint::u32s_from_bytes(frontier_slot)[..]).enq().unwrap();
+        self.cl.state.write(&checkpoint::u32s_from_bytes(rest)[..]).enq().unwrap();
+    }
+}
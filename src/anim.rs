@@ -0,0 +1,228 @@
+use crc32fast;
+use deflate;
+use gif;
+use image;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use backend::Canvas;
+use config::AnimOptions;
+
+// NeuQuant sample rate for the gif palettes, 1 (best) to 30 (fastest).
+const GIF_SPEED: i32 = 10;
+
+// An animated GIF or APNG written a frame at a time,
+// so a long recording never needs to be in memory or on disk as PNGs.
+pub struct Animation {
+    writer: Writer,
+    options: AnimOptions,
+    // Frames offered so far, including skipped ones.
+    offered: u64,
+}
+
+enum Writer {
+    Gif(gif::Encoder<BufWriter<File>>),
+    Apng(Apng),
+}
+
+impl Animation {
+    // The format comes from the extension: .gif, or .png/.apng.
+    pub fn create(path: &Path, dims: (u32, u32), options: AnimOptions) -> Animation {
+        let ext = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+        let file = BufWriter::new(File::create(path)
+            .unwrap_or_else(|err| panic!("create {}: {}", path.display(), err)));
+        let writer = match ext.as_ref().map(|ext| ext.as_str()) {
+            Some("gif") => {
+                if dims.0 > 0xffff || dims.1 > 0xffff {
+                    panic!("gifs can be at most 65535x65535, got {}x{}", dims.0, dims.1);
+                }
+                let mut encoder = gif::Encoder::new(file, dims.0 as u16, dims.1 as u16, &[]).expect("gif header");
+                encoder.set_repeat(match options.loops {
+                    0 => gif::Repeat::Infinite,
+                    n => gif::Repeat::Finite(n),
+                }).expect("gif repeat");
+                Writer::Gif(encoder)
+            },
+            Some("png") | Some("apng") => Writer::Apng(Apng::new(file, dims, options.loops as u32)),
+            _ => panic!("animations must be .gif, .png or .apng, got {}", path.display()),
+        };
+        Animation {
+            writer: writer,
+            options: options,
+            offered: 0,
+        }
+    }
+
+    pub fn add(&mut self, canvas: &Canvas) {
+        let keep = self.offered % (self.options.skip as u64 + 1) == 0;
+        self.offered += 1;
+        if !keep {
+            return;
+        }
+        let delay_ms = self.options.delay_ms;
+        match self.writer {
+            Writer::Gif(ref mut encoder) => {
+                let mut rgba = canvas.clone().into_raw();
+                let mut frame = gif::Frame::from_rgba_speed(
+                    canvas.width() as u16, canvas.height() as u16, &mut rgba, GIF_SPEED);
+                // Centiseconds.
+                frame.delay = (delay_ms / 10).min(0xffff) as u16;
+                encoder.write_frame(&frame).expect("write gif frame");
+            },
+            Writer::Apng(ref mut apng) => apng.add(canvas, delay_ms),
+        }
+    }
+}
+
+// https://wiki.mozilla.org/APNG_Specification
+// The frame count goes in the header, so it gets patched in on drop.
+struct Apng {
+    file: BufWriter<File>,
+    dims: (u32, u32),
+    frames: u32,
+    loops: u32,
+    // The next fcTL/fdAT sequence number.
+    sequence: u32,
+    // Where the acTL chunk is.
+    actl_at: u64,
+}
+
+fn actl(frames: u32, loops: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&frames.to_be_bytes());
+    data.extend_from_slice(&loops.to_be_bytes());
+    data
+}
+
+fn write_chunk<W: Write>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    w.write_all(&crc.finalize().to_be_bytes())
+}
+
+impl Apng {
+    fn new(mut file: BufWriter<File>, dims: (u32, u32), loops: u32) -> Apng {
+        file.write_all(b"\x89PNG\r\n\x1a\n").unwrap();
+
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&dims.0.to_be_bytes());
+        ihdr.extend_from_slice(&dims.1.to_be_bytes());
+        // 8 bit rgba, deflate, adaptive filtering, no interlace.
+        ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+        write_chunk(&mut file, b"IHDR", &ihdr).expect("write apng");
+
+        let actl_at = file.seek(SeekFrom::Current(0)).unwrap();
+        write_chunk(&mut file, b"acTL", &actl(0, loops)).expect("write apng");
+
+        Apng {
+            file: file,
+            dims: dims,
+            frames: 0,
+            loops: loops,
+            sequence: 0,
+            actl_at: actl_at,
+        }
+    }
+
+    fn add(&mut self, canvas: &Canvas, delay_ms: u32) {
+        let (w, h) = self.dims;
+
+        let mut fctl = Vec::new();
+        fctl.extend_from_slice(&self.sequence.to_be_bytes());
+        fctl.extend_from_slice(&w.to_be_bytes());
+        fctl.extend_from_slice(&h.to_be_bytes());
+        // x and y offsets.
+        fctl.extend_from_slice(&[0; 8]);
+        fctl.extend_from_slice(&(delay_ms.min(0xffff) as u16).to_be_bytes());
+        fctl.extend_from_slice(&1000u16.to_be_bytes());
+        // Dispose none, blend source: each frame replaces the last.
+        fctl.extend_from_slice(&[0, 0]);
+        write_chunk(&mut self.file, b"fcTL", &fctl).expect("write apng");
+        self.sequence += 1;
+
+        // Every row with filter type 0 (none).
+        let stride = (w * 4) as usize;
+        let mut raw = Vec::with_capacity((stride + 1) * h as usize);
+        for row in canvas.chunks(stride) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+        let compressed = deflate::deflate_bytes_zlib(&raw);
+
+        if self.frames == 0 {
+            // The first frame doubles as the still image for plain png viewers.
+            write_chunk(&mut self.file, b"IDAT", &compressed).expect("write apng");
+        } else {
+            let mut fdat = Vec::with_capacity(compressed.len() + 4);
+            fdat.extend_from_slice(&self.sequence.to_be_bytes());
+            fdat.extend_from_slice(&compressed);
+            write_chunk(&mut self.file, b"fdAT", &fdat).expect("write apng");
+            self.sequence += 1;
+        }
+        self.frames += 1;
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        write_chunk(&mut self.file, b"IEND", &[])?;
+        // Now that the frame count is known, write the acTL again over the placeholder.
+        self.file.seek(SeekFrom::Start(self.actl_at))?;
+        write_chunk(&mut self.file, b"acTL", &actl(self.frames, self.loops))?;
+        self.file.flush()
+    }
+}
+
+impl Drop for Apng {
+    // This can run while the sim thread unwinds, where another panic would abort.
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            printlnc!(red: "apng: couldn't finish the file: {}", err);
+        }
+    }
+}
+
+// Turn the result_NNNNNN.png frames of a recording into an animation.
+// Mask and score images in the same directory are left out.
+pub fn export(dir: &Path, out: &Path, options: AnimOptions) {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir).expect("read frames dir")
+        .map(|entry| entry.expect("read frames dir").path())
+        .filter(|path| {
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+            name.starts_with("result_") && name.ends_with(".png")
+        })
+        .collect();
+    // The frame numbers are zero padded.
+    paths.sort();
+    if paths.is_empty() {
+        panic!("no result_NNNNNN.png frames in {}", dir.display());
+    }
+    printlnc!(white_bold: "exporting {} frames from {} to {}", paths.len(), dir.display(), out.display());
+
+    let mut animation: Option<Animation> = None;
+    // The first frame's, which the animation is made at.
+    let mut dims = None;
+    for path in &paths {
+        let canvas = image::open(path)
+            .unwrap_or_else(|err| panic!("load {}: {}", path.display(), err))
+            .to_rgba();
+        // Out dirs get reused, by runs of other sizes too.
+        let (w, h) = *dims.get_or_insert(canvas.dimensions());
+        if canvas.dimensions() != (w, h) {
+            printlnc!(red: "skipping {}: it's {}x{}, not {}x{} like the first frame",
+                      path.display(), canvas.width(), canvas.height(), w, h);
+            continue;
+        }
+        animation.get_or_insert_with(|| Animation::create(out, (w, h), options))
+            .add(&canvas);
+    }
+    // Finish the file.
+    drop(animation);
+    printlnc!(green: "wrote {}", out.display());
+}
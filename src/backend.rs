@@ -0,0 +1,120 @@
+use image;
+use common::Cursor;
+use config::{Config, BackendKind};
+use cpu::CpuBackend;
+use gpu::GpuBackend;
+use mode::KernelSpec;
+use stencil::Stencil;
+
+pub type Canvas = image::ImageBuffer<image::Rgba<u8>, Vec<u8>>;
+// Fill generation of a pixel, 0 for empty. Each random drop starts a new one,
+// so it's wide enough to never wrap. The gpu stores it as an R/UnsignedInt32 image.
+pub type MaskVal = u32;
+pub type Mask = image::ImageBuffer<image::Luma<MaskVal>, Vec<MaskVal>>;
+// The canvas at full precision, as the pastiche backends keep it. Rounding every
+// step to 8 bits biases the random walk of the colors and bands long runs, so
+// only what's read back for display and saving is 8 bit.
+pub type FloatCanvas = image::ImageBuffer<image::Rgba<f32>, Vec<f32>>;
+
+// What read_imagef returns for a UnormInt8 image.
+pub fn unorm(px: &image::Rgba<u8>) -> [f32; 4] {
+    [px[0] as f32 / 255.0, px[1] as f32 / 255.0, px[2] as f32 / 255.0, px[3] as f32 / 255.0]
+}
+
+// What write_imagef stores into a UnormInt8 image (saturate, round to nearest even).
+pub fn to_unorm8(c: [f32; 4]) -> [u8; 4] {
+    let mut out = [0u8; 4];
+    for i in 0..4 {
+        let v = (c[i] * 255.0).max(0.0).min(255.0);
+        let mut r = v.round();
+        if (v - v.trunc()) == 0.5 && r % 2.0 != 0.0 {
+            r -= 1.0;
+        }
+        out[i] = r as u8;
+    }
+    out
+}
+
+pub fn float_canvas(canvas: &Canvas) -> FloatCanvas {
+    image::ImageBuffer::from_fn(canvas.width(), canvas.height(), |x, y| {
+        image::Rgba(unorm(canvas.get_pixel(x, y)))
+    })
+}
+
+// Down to 8 bits for display and saving.
+pub fn tone_map(canvas: &FloatCanvas, out: &mut Canvas) {
+    for (src, dst) in canvas.chunks(4).zip(out.chunks_mut(4)) {
+        dst.copy_from_slice(&to_unorm8([src[0], src[1], src[2], src[3]]));
+    }
+}
+
+// Per-frame inputs to a step.
+#[derive(Clone, Default)]
+pub struct StepParams {
+    pub time_ms: u32,
+    pub cursor: Cursor,
+}
+
+// Something that can run a mode's step.
+// The backend owns the canvas and mask. The frame loop pokes
+// seeds in with `place_pixel` and reads the state back to display or save it.
+pub trait Backend {
+    fn describe(&self) -> String;
+
+    // Replace the subject. Must be the canvas dims.
+    fn set_subject(&mut self, subject: &Canvas);
+
+    // Keep growth to where `stencil` allows. Everywhere until this is called.
+    // Kernels that don't take a stencil ignore it.
+    fn set_stencil(&mut self, _stencil: &Stencil) {}
+
+    // Paint a pixel and mark it filled with generation `mask_value`.
+    fn place_pixel(&mut self, x: u32, y: u32, color: image::Rgba<u8>, mask_value: MaskVal);
+
+    // Rebuild kernels from the current .cl sources, keeping all state.
+    // On failure the old kernels keep running and the build log comes back.
+    fn reload(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    // The crc32 of the .cl source the running kernels were built from.
+    // None for backends that don't build any.
+    fn source_crc(&self) -> Option<u32> {
+        None
+    }
+
+    // Advance the simulation by one frame.
+    fn step(&mut self, params: &StepParams);
+
+    fn read_canvas(&mut self, canvas: &mut Canvas);
+
+    fn read_mask(&mut self, mask: &mut Mask);
+
+    // State beyond the canvas and mask, like rng counters, for checkpoints.
+    // It goes back through `write_state` on a backend created with the saved canvas and mask.
+    fn read_state(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn write_state(&mut self, _state: &[u8]) {}
+}
+
+// Run `spec` on the configured backend.
+pub fn create(config: &Config, spec: &KernelSpec, canvas: Canvas, mask: Mask, subject: &Canvas) -> Box<dyn Backend> {
+    match config.backend {
+        BackendKind::Gpu => Box::new(GpuBackend::new(config, spec, canvas, mask, subject)),
+        BackendKind::Cpu => {
+            if spec.name != "pastiche" {
+                panic!("the cpu backend only implements the pastiche kernel, not '{}'", spec.name);
+            }
+            Box::new(CpuBackend::new(config, canvas, mask, subject))
+        },
+    }
+}
+
+// For modes that only run on OpenCL.
+pub fn require_gpu(config: &Config, mode: &str) {
+    if let BackendKind::Cpu = config.backend {
+        panic!("the cpu backend can't run {}", mode);
+    }
+}
@@ -1,28 +1,447 @@
 use camera_capture;
 use image;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use image::buffer::ConvertBuffer;
+use image::imageops;
+use config::{Fit, Subject};
 
-pub type CamImg = image::ImageBuffer<image::Rgb<u8>, camera_capture::Frame>;
-
-pub fn cam_loop(dims: (u32, u32), sender: mpsc::SyncSender<CamImg>) {
-    let cam = camera_capture::create(0).expect("get camera");
-    let cam = cam
-        .fps(30.0).expect("cam fps")
-        .resolution(dims.0, dims.1).expect("cam resolution")
-        .start().expect("start camera");
-    for image in cam {
-        match sender.try_send(image) {
-            Ok(_) => {},
-            Err(mpsc::TrySendError::Full(_)) => {},
-            Err(mpsc::TrySendError::Disconnected(_)) => {
-                eprintln!("cam disconnected");
-                return
+pub type CamImg = image::RgbImage;
+
+// Somewhere subject frames come from.
+pub trait SubjectSource {
+    fn describe(&self) -> String;
+
+    // Live sources drop frames nobody asked for.
+    // Recorded ones wait until the sim takes a frame, so runs are reproducible.
+    fn is_live(&self) -> bool {
+        false
+    }
+
+    // The next frame, or None when there are no more.
+    fn next_frame(&mut self) -> Option<CamImg>;
+
+    // Go back to the first frame. False if that makes no sense for this source.
+    fn rewind(&mut self) -> bool {
+        false
+    }
+}
+
+pub fn open(subject: &Subject, dims: (u32, u32)) -> Box<dyn SubjectSource> {
+    match *subject {
+        Subject::Camera => Box::new(Camera::new(dims)),
+        Subject::Image(ref path) => Box::new(StillImage::new(path)),
+        Subject::Frames(ref dir) => Box::new(FrameDir::new(dir)),
+        Subject::Y4m(ref path) => Box::new(Y4m::new(path)),
+        Subject::Raw(ref path) => Box::new(RawVideo::new(path, dims)),
+    }
+}
+
+// What `subject` is, without taking over the camera to find out.
+pub fn describe(subject: &Subject, dims: (u32, u32)) -> String {
+    match *subject {
+        Subject::Camera => "camera".to_owned(),
+        _ => open(subject, dims).describe(),
+    }
+}
+
+// Feed frames from the subject to the sim until it hangs up.
+// Recorded subjects hold their last frame once they run out,
+// so a restarted sim still has something to start from.
+// Recorded sources drop their first `skip` frames, for resumed runs.
+// With `tileable`, frames are made `seamless` on the way.
+pub fn source_loop(subject: Subject, dims: (u32, u32), fit_policy: Fit, looping: bool, tileable: bool,
+                   mut skip: u64, sender: mpsc::SyncSender<CamImg>) {
+    let fit = |image: CamImg| {
+        let image = fit(image, dims, fit_policy);
+        if tileable { seamless(&image) } else { image }
+    };
+    let mut source = open(&subject, dims);
+    printlnc!(white_bold: "subject: {}", source.describe());
+    if source.is_live() {
+        while let Some(image) = source.next_frame() {
+            match sender.try_send(fit(image)) {
+                Ok(_) => {},
+                Err(mpsc::TrySendError::Full(_)) => {},
+                Err(mpsc::TrySendError::Disconnected(_)) => {
+                    eprintln!("cam disconnected");
+                    return
+                }
             }
         }
+        printlnc!(red: "subject ended");
+        return;
+    }
+
+    // Unfitted, so skipping doesn't pay for the resize.
+    let mut last: Option<CamImg> = None;
+    // Whether a frame came since the last rewind, if any. Without one, rewinding again would spin.
+    let mut fresh = true;
+    loop {
+        let image = match source.next_frame() {
+            Some(image) => image,
+            None => {
+                if looping && !fresh {
+                    panic!("subject has no frames after rewinding: {}", source.describe());
+                }
+                if looping && source.rewind() {
+                    fresh = false;
+                    continue;
+                }
+                break;
+            },
+        };
+        fresh = true;
+        last = Some(image.clone());
+        if skip > 0 {
+            skip -= 1;
+            continue;
+        }
+        if sender.send(fit(image)).is_err() {
+            return;
+        }
+    }
+
+    let last = fit(last.unwrap_or_else(|| panic!("subject has no frames: {}", source.describe())));
+    printlnc!(royal_blue: "subject ended, holding the last frame");
+    while sender.send(last.clone()).is_ok() {}
+}
+
+const FIT_FILTER: imageops::FilterType = imageops::FilterType::Triangle;
+
+// Make a subject frame exactly `dims`, whatever size it came in at.
+pub fn fit(img: CamImg, dims: (u32, u32), policy: Fit) -> CamImg {
+    let (w, h) = img.dimensions();
+    if (w, h) == dims {
+        return img;
+    }
+    // Scale so both sides are at least (cover) or at most (!cover) the canvas.
+    let scaled = |cover: bool| -> CamImg {
+        let (sx, sy) = (dims.0 as f64 / w as f64, dims.1 as f64 / h as f64);
+        let scale = if cover { sx.max(sy) } else { sx.min(sy) };
+        let side = |len: u32, canvas: u32| -> u32 {
+            let len = (len as f64 * scale).round() as u32;
+            if cover { len.max(canvas) } else { len.max(1).min(canvas) }
+        };
+        imageops::resize(&img, side(w, dims.0), side(h, dims.1), FIT_FILTER)
+    };
+    match policy {
+        Fit::Stretch => imageops::resize(&img, dims.0, dims.1, FIT_FILTER),
+        Fit::Crop => {
+            let mut big = scaled(true);
+            let (bw, bh) = big.dimensions();
+            imageops::crop(&mut big, (bw - dims.0) / 2, (bh - dims.1) / 2, dims.0, dims.1).to_image()
+        },
+        Fit::Letterbox(fill) => {
+            let small = scaled(false);
+            let (sw, sh) = small.dimensions();
+            let mut out = CamImg::from_pixel(dims.0, dims.1, image::Rgb(fill));
+            imageops::replace(&mut out, &small, (dims.0 - sw) / 2, (dims.1 - sh) / 2);
+            out
+        },
+        Fit::Tile => CamImg::from_fn(dims.0, dims.1, |x, y| *img.get_pixel(x % w, y % h)),
     }
 }
 
+// Cross-fade a frame with itself shifted by half, all the way at the edges and
+// not at all in the middle, so its left edge runs into its right and its top
+// into its bottom. A subject like that doesn't leave seams in a wrapped canvas.
+pub fn seamless(img: &CamImg) -> CamImg {
+    let (w, h) = img.dimensions();
+    let weight = |v: u32, len: u32| ((v as f32 + 0.5) / len as f32 - 0.5).abs() * 2.0;
+    let blend = |a: &image::Rgb<u8>, b: &image::Rgb<u8>, t: f32| {
+        let mix = |i: usize| (a[i] as f32 + (b[i] as f32 - a[i] as f32) * t).round() as u8;
+        image::Rgb([mix(0), mix(1), mix(2)])
+    };
+    let across = CamImg::from_fn(w, h, |x, y| {
+        blend(img.get_pixel(x, y), img.get_pixel((x + w / 2) % w, y), weight(x, w))
+    });
+    CamImg::from_fn(w, h, |x, y| {
+        blend(across.get_pixel(x, y), across.get_pixel(x, (y + h / 2) % h), weight(y, h))
+    })
+}
+
 pub fn convert(img: CamImg) -> image::ImageBuffer<image::Rgba<u8>, Vec<u8>> {
     img.convert()
 }
+
+struct Camera {
+    frames: Box<dyn Iterator<Item = image::ImageBuffer<image::Rgb<u8>, camera_capture::Frame>>>,
+}
+
+impl Camera {
+    fn new(dims: (u32, u32)) -> Camera {
+        let cam = camera_capture::create(0).expect("get camera");
+        let cam = cam
+            .fps(30.0).expect("cam fps")
+            .resolution(dims.0, dims.1).expect("cam resolution")
+            .start().expect("start camera");
+        Camera {
+            frames: Box::new(cam),
+        }
+    }
+}
+
+impl SubjectSource for Camera {
+    fn describe(&self) -> String {
+        "camera".to_owned()
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    fn next_frame(&mut self) -> Option<CamImg> {
+        self.frames.next().map(|image| {
+            let (w, h) = image.dimensions();
+            CamImg::from_raw(w, h, image.into_raw().to_vec()).unwrap()
+        })
+    }
+}
+
+// One image, once.
+struct StillImage {
+    path: PathBuf,
+    image: Option<CamImg>,
+}
+
+impl StillImage {
+    fn new(path: &Path) -> StillImage {
+        StillImage {
+            path: path.to_owned(),
+            image: Some(image::open(path).expect("load subject").to_rgb()),
+        }
+    }
+}
+
+impl SubjectSource for StillImage {
+    fn describe(&self) -> String {
+        format!("image {}", self.path.display())
+    }
+
+    fn next_frame(&mut self) -> Option<CamImg> {
+        self.image.take()
+    }
+}
+
+// The number in a name like frame_0012.png.
+// The last run of digits, so a prefix like take2_ doesn't get in the way.
+fn frame_number(path: &Path) -> Option<u64> {
+    let stem = path.file_stem()?.to_str()?;
+    let digits: String = stem.chars().rev()
+        .skip_while(|c| !c.is_ascii_digit())
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.chars().rev().collect::<String>().parse().ok()
+}
+
+// A directory of numbered image files, in number order.
+struct FrameDir {
+    dir: PathBuf,
+    paths: Vec<PathBuf>,
+    next: usize,
+}
+
+impl FrameDir {
+    fn new(dir: &Path) -> FrameDir {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir).expect("read subject dir")
+            .map(|entry| entry.expect("read subject dir").path())
+            .filter(|path| path.is_file() && image::ImageFormat::from_path(path).is_ok())
+            .collect();
+        paths.sort_by_key(|path| (frame_number(path).unwrap_or(::std::u64::MAX), path.clone()));
+        if paths.is_empty() {
+            panic!("no images in subject dir {}", dir.display());
+        }
+        FrameDir {
+            dir: dir.to_owned(),
+            paths: paths,
+            next: 0,
+        }
+    }
+}
+
+impl SubjectSource for FrameDir {
+    fn describe(&self) -> String {
+        format!("{} frames in {}", self.paths.len(), self.dir.display())
+    }
+
+    fn next_frame(&mut self) -> Option<CamImg> {
+        let path = self.paths.get(self.next)?;
+        self.next += 1;
+        Some(image::open(path)
+             .unwrap_or_else(|err| panic!("load {}: {}", path.display(), err))
+             .to_rgb())
+    }
+
+    fn rewind(&mut self) -> bool {
+        self.next = 0;
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Chroma {
+    C420,
+    C444,
+    Mono,
+}
+
+// A YUV4MPEG2 file, like ffmpeg writes with `-f yuv4mpegpipe`.
+// https://wiki.multimedia.cx/index.php/YUV4MPEG2
+struct Y4m {
+    path: PathBuf,
+    reader: BufReader<File>,
+    dims: (u32, u32),
+    chroma: Chroma,
+    // Where the first FRAME starts.
+    data_start: u64,
+}
+
+impl Y4m {
+    fn new(path: &Path) -> Y4m {
+        let mut reader = BufReader::new(File::open(path).expect("open subject video"));
+        let mut header = String::new();
+        reader.read_line(&mut header).expect("read y4m header");
+        let mut params = header.trim_end().split(' ');
+        if params.next() != Some("YUV4MPEG2") {
+            panic!("{} is not a y4m file", path.display());
+        }
+        let (mut width, mut height) = (0, 0);
+        let mut chroma = Chroma::C420;
+        for param in params {
+            if param.is_empty() {
+                continue;
+            }
+            let (tag, value) = param.split_at(1);
+            match tag {
+                "W" => width = value.parse().expect("y4m width"),
+                "H" => height = value.parse().expect("y4m height"),
+                "C" => chroma = match value {
+                    "420" | "420jpeg" | "420paldv" | "420mpeg2" => Chroma::C420,
+                    "444" => Chroma::C444,
+                    "mono" => Chroma::Mono,
+                    other => panic!("unsupported y4m colorspace C{}", other),
+                },
+                // Frame rate, interlacing, aspect and extensions don't matter here.
+                _ => {},
+            }
+        }
+        if width == 0 || height == 0 {
+            panic!("y4m header without dims: {}", header.trim_end());
+        }
+        let data_start = header.len() as u64;
+        Y4m {
+            path: path.to_owned(),
+            reader: reader,
+            dims: (width, height),
+            chroma: chroma,
+            data_start: data_start,
+        }
+    }
+
+    fn chroma_dims(&self) -> (u32, u32) {
+        let (w, h) = self.dims;
+        match self.chroma {
+            Chroma::C420 => ((w + 1) / 2, (h + 1) / 2),
+            Chroma::C444 => (w, h),
+            Chroma::Mono => (0, 0),
+        }
+    }
+}
+
+// BT.601 studio range, what y4m usually holds.
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> image::Rgb<u8> {
+    let c = 1.164 * (y as f32 - 16.0);
+    let d = u as f32 - 128.0;
+    let e = v as f32 - 128.0;
+    let clamp = |x: f32| x.round().max(0.0).min(255.0) as u8;
+    image::Rgb([
+        clamp(c + 1.596 * e),
+        clamp(c - 0.392 * d - 0.813 * e),
+        clamp(c + 2.017 * d),
+    ])
+}
+
+impl SubjectSource for Y4m {
+    fn describe(&self) -> String {
+        format!("y4m {} ({}x{} {:?})", self.path.display(), self.dims.0, self.dims.1, self.chroma)
+    }
+
+    fn next_frame(&mut self) -> Option<CamImg> {
+        let mut frame_header = String::new();
+        if self.reader.read_line(&mut frame_header).expect("read y4m frame") == 0 {
+            return None;
+        }
+        if !frame_header.starts_with("FRAME") {
+            panic!("bad y4m frame header: {:?}", frame_header);
+        }
+
+        let (w, h) = self.dims;
+        let (cw, ch) = self.chroma_dims();
+        let mut luma = vec![0u8; (w * h) as usize];
+        let mut cb = vec![128u8; (cw * ch) as usize];
+        let mut cr = vec![128u8; (cw * ch) as usize];
+        // A short last frame just ends the video.
+        if self.reader.read_exact(&mut luma).is_err()
+            || self.reader.read_exact(&mut cb).is_err()
+            || self.reader.read_exact(&mut cr).is_err() {
+            return None;
+        }
+
+        let chroma = self.chroma;
+        Some(CamImg::from_fn(w, h, |x, y| {
+            let (cx, cy) = match chroma {
+                Chroma::C420 => (x / 2, y / 2),
+                Chroma::C444 => (x, y),
+                Chroma::Mono => return yuv_to_rgb(luma[(y * w + x) as usize], 128, 128),
+            };
+            let ci = (cy * cw + cx) as usize;
+            yuv_to_rgb(luma[(y * w + x) as usize], cb[ci], cr[ci])
+        }))
+    }
+
+    fn rewind(&mut self) -> bool {
+        self.reader.seek(SeekFrom::Start(self.data_start)).expect("rewind y4m");
+        true
+    }
+}
+
+// Headerless rgb24 frames at the canvas dims,
+// like ffmpeg writes with `-f rawvideo -pix_fmt rgb24 -s WxH`.
+struct RawVideo {
+    path: PathBuf,
+    reader: BufReader<File>,
+    dims: (u32, u32),
+}
+
+impl RawVideo {
+    fn new(path: &Path, dims: (u32, u32)) -> RawVideo {
+        RawVideo {
+            path: path.to_owned(),
+            reader: BufReader::new(File::open(path).expect("open subject video")),
+            dims: dims,
+        }
+    }
+}
+
+impl SubjectSource for RawVideo {
+    fn describe(&self) -> String {
+        format!("raw rgb24 {} at {}x{}", self.path.display(), self.dims.0, self.dims.1)
+    }
+
+    fn next_frame(&mut self) -> Option<CamImg> {
+        let mut buf = vec![0u8; (self.dims.0 * self.dims.1 * 3) as usize];
+        match self.reader.read_exact(&mut buf) {
+            Ok(()) => Some(CamImg::from_raw(self.dims.0, self.dims.1, buf).unwrap()),
+            Err(_) => None,
+        }
+    }
+
+    fn rewind(&mut self) -> bool {
+        self.reader.seek(SeekFrom::Start(0)).expect("rewind raw video");
+        true
+    }
+}
@@ -0,0 +1,239 @@
+use image;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::mem;
+use std::path::Path;
+use backend::{Backend, Canvas, Mask, MaskVal};
+use common::Cursor;
+use config;
+use config::Config;
+use mode::Mode;
+
+// Everything needed to carry on a run exactly where it left off.
+// Host randomness comes from the seed and frame number, so the only
+// rng state to keep is on the backend.
+//
+// The file is a line of magic, then sections of `name length\n` and that many bytes:
+//   flags     what `config::sim_flags` gives, one per line
+//   counters  `frame`, `time_ms` and `cursor` lines
+//   canvas    raw rgba, 8 bit. Backends that keep more put theirs in their state.
+//   mask      raw little endian MaskVal
+//   backend   whatever the backend's read_state gives
+//   mode      whatever the mode's read_state gives
+pub struct Checkpoint {
+    // The next frame to run.
+    pub frame: u64,
+    // The kernel clock at that frame.
+    pub time_ms: u64,
+    // Where the fake mouse was.
+    pub fake_cursor: Cursor,
+    pub canvas: Canvas,
+    pub mask: Mask,
+    pub backend: Vec<u8>,
+    pub mode: Vec<u8>,
+}
+
+// Just the small sections at the front.
+pub struct Header {
+    pub flags: Vec<String>,
+    pub frame: u64,
+    pub time_ms: u64,
+    pub fake_cursor: Cursor,
+}
+
+const MAGIC: &str = "pastiche checkpoint 3";
+
+fn write_section<W: Write>(w: &mut W, name: &str, data: &[u8]) {
+    write!(w, "{} {}\n", name, data.len()).unwrap();
+    w.write_all(data).unwrap();
+}
+
+// The next section, or None at the end of the file.
+fn read_section<R: BufRead>(r: &mut R, path: &Path) -> Option<(String, Vec<u8>)> {
+    let mut line = String::new();
+    if r.read_line(&mut line).expect("read checkpoint") == 0 {
+        return None;
+    }
+    let bad = || -> ! { panic!("{} is not a pastiche checkpoint (at {:?})", path.display(), line.trim_end()) };
+    let mut parts = line.trim_end().split(' ');
+    let name = parts.next().unwrap_or_else(|| bad()).to_owned();
+    let len: usize = parts.next().and_then(|len| len.parse().ok()).unwrap_or_else(|| bad());
+    let mut data = vec![0; len];
+    r.read_exact(&mut data).expect("read checkpoint");
+    Some((name, data))
+}
+
+fn open(path: &Path) -> BufReader<File> {
+    let mut r = BufReader::new(File::open(path)
+        .unwrap_or_else(|err| panic!("open checkpoint {}: {}", path.display(), err)));
+    let mut magic = String::new();
+    r.read_line(&mut magic).expect("read checkpoint");
+    if magic.trim_end() != MAGIC {
+        panic!("{} is not a pastiche checkpoint", path.display());
+    }
+    r
+}
+
+fn read_header_from<R: BufRead>(r: &mut R, path: &Path) -> Header {
+    let mut header = Header {
+        flags: Vec::new(),
+        frame: 0,
+        time_ms: 0,
+        fake_cursor: Default::default(),
+    };
+    for section in &["flags", "counters"] {
+        let (name, data) = read_section(r, path).unwrap_or_else(|| panic!("checkpoint {} is cut short", path.display()));
+        if name != *section {
+            panic!("checkpoint {}: expected {} but found {}", path.display(), section, name);
+        }
+        let text = String::from_utf8(data).expect("checkpoint text");
+        if name == "flags" {
+            header.flags = text.lines().map(|line| line.to_owned()).collect();
+            continue;
+        }
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let num = |i: usize| -> u64 {
+                fields.get(i).and_then(|s| s.parse().ok())
+                    .unwrap_or_else(|| panic!("checkpoint {}: bad line {:?}", path.display(), line))
+            };
+            match fields[0] {
+                "frame" => header.frame = num(1),
+                "time_ms" => header.time_ms = num(1),
+                "cursor" => header.fake_cursor = Cursor {
+                    enabled: num(1) != 0,
+                    x: num(2) as u32,
+                    y: num(3) as u32,
+                    pressed: num(4) != 0,
+                },
+                _ => {},
+            }
+        }
+    }
+    header
+}
+
+// Read the settings and counters without loading the images.
+pub fn read_header(path: &Path) -> Header {
+    read_header_from(&mut open(path), path)
+}
+
+// `dims` must be what the saved flags say, which holds once they're applied to the config.
+pub fn load(path: &Path, dims: (u32, u32)) -> Checkpoint {
+    let mut r = open(path);
+    let header = read_header_from(&mut r, path);
+
+    let mut canvas = None;
+    let mut mask = None;
+    let mut backend = Vec::new();
+    let mut mode = Vec::new();
+    while let Some((name, data)) = read_section(&mut r, path) {
+        match name.as_str() {
+            "canvas" => canvas = Some(image::ImageBuffer::from_raw(dims.0, dims.1, data)
+                .unwrap_or_else(|| panic!("checkpoint {}: canvas isn't {}x{}", path.display(), dims.0, dims.1))),
+            "mask" => mask = Some(image::ImageBuffer::from_raw(dims.0, dims.1, mask_from_bytes(&data))
+                .unwrap_or_else(|| panic!("checkpoint {}: mask isn't {}x{}", path.display(), dims.0, dims.1))),
+            "backend" => backend = data,
+            "mode" => mode = data,
+            _ => {},
+        }
+    }
+
+    Checkpoint {
+        frame: header.frame,
+        time_ms: header.time_ms,
+        fake_cursor: header.fake_cursor,
+        canvas: canvas.unwrap_or_else(|| panic!("checkpoint {} has no canvas", path.display())),
+        mask: mask.unwrap_or_else(|| panic!("checkpoint {} has no mask", path.display())),
+        backend: backend,
+        mode: mode,
+    }
+}
+
+// Write to a temporary file first so a crash mid-save leaves the last checkpoint intact.
+pub fn save(path: &Path, config: &Config, frame: u64, time_ms: u64, fake_cursor: &Cursor,
+            backend: &mut dyn Backend, mode: &dyn Mode) {
+    let dims = config.dims;
+    let mut canvas: Canvas = image::ImageBuffer::new(dims.0, dims.1);
+    let mut mask: Mask = image::ImageBuffer::new(dims.0, dims.1);
+    backend.read_canvas(&mut canvas);
+    backend.read_mask(&mut mask);
+
+    let counters = format!("frame {}\ntime_ms {}\ncursor {} {} {} {}\n",
+                           frame, time_ms,
+                           fake_cursor.enabled as u8, fake_cursor.x, fake_cursor.y, fake_cursor.pressed as u8);
+
+    let tmp = path.with_extension("tmp");
+    {
+        let mut w = BufWriter::new(File::create(&tmp)
+            .unwrap_or_else(|err| panic!("create checkpoint {}: {}", tmp.display(), err)));
+        write!(w, "{}\n", MAGIC).unwrap();
+        write_section(&mut w, "flags", config::sim_flags(config).join("\n").as_bytes());
+        write_section(&mut w, "counters", counters.as_bytes());
+        write_section(&mut w, "canvas", &canvas);
+        write_section(&mut w, "mask", &mask_to_bytes(&mask));
+        write_section(&mut w, "backend", &backend.read_state());
+        write_section(&mut w, "mode", &mode.read_state());
+        w.flush().unwrap();
+    }
+    fs::rename(&tmp, path).expect("move checkpoint into place");
+    printlnc!(green: "checkpoint: frame {} to {}", frame, path.display());
+}
+
+fn mask_to_bytes(mask: &Mask) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(mask.len() * mem::size_of::<MaskVal>());
+    for v in mask.iter() {
+        bytes.extend_from_slice(&v.to_le_bytes());
+    }
+    bytes
+}
+
+fn mask_from_bytes(bytes: &[u8]) -> Vec<MaskVal> {
+    bytes.chunks(mem::size_of::<MaskVal>())
+        .map(|chunk| {
+            let mut le = [0; mem::size_of::<MaskVal>()];
+            le.copy_from_slice(chunk);
+            MaskVal::from_le_bytes(le)
+        })
+        .collect()
+}
+
+// For backends to pack their state.
+pub fn u32s_to_bytes(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+}
+
+pub fn u32s_from_bytes(bytes: &[u8]) -> Vec<u32> {
+    bytes.chunks(4).map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect()
+}
+
+pub fn u64s_to_bytes(values: &[u64]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+}
+
+pub fn u64s_from_bytes(bytes: &[u8]) -> Vec<u64> {
+    bytes.chunks(8).map(|c| {
+        let mut le = [0; 8];
+        le.copy_from_slice(c);
+        u64::from_le_bytes(le)
+    }).collect()
+}
+
+pub fn f32s_to_bytes(values: &[f32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+}
+
+pub fn f32s_from_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes.chunks(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect()
+}
+
+// Backend state that starts with a full precision canvas: the canvas and what follows it.
+pub fn split_canvas_state(state: &[u8], dims: (u32, u32)) -> (Vec<f32>, &[u8]) {
+    let len = (dims.0 * dims.1) as usize * 4 * 4;
+    if state.len() < len {
+        panic!("checkpoint backend state is too short for a {}x{} canvas", dims.0, dims.1);
+    }
+    let (canvas, rest) = state.split_at(len);
+    (f32s_from_bytes(canvas), rest)
+}
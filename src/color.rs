@@ -0,0 +1,174 @@
+use config::ColorSpace;
+
+// The color spaces of main.cl, for the cpu backend and for scoring saved results.
+// Keep in sync with the COLOR_SPACE_* functions there.
+// Each space is scaled so black to white is 1.
+
+pub type Color = [f32; 3];
+
+fn srgb_to_linear1(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb1(c: f32) -> f32 {
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+}
+
+fn srgb_to_linear(c: Color) -> Color {
+    [srgb_to_linear1(c[0]), srgb_to_linear1(c[1]), srgb_to_linear1(c[2])]
+}
+
+// Negative channels are out of gamut anyway, keep their sign so they stay that way.
+fn linear_to_srgb(c: Color) -> Color {
+    let f = |v: f32| v.signum() * linear_to_srgb1(v.abs());
+    [f(c[0]), f(c[1]), f(c[2])]
+}
+
+// CIELAB with a D65 white.
+const LAB_DELTA: f32 = 6.0 / 29.0;
+
+fn lab_f(t: f32) -> f32 {
+    if t > LAB_DELTA * LAB_DELTA * LAB_DELTA {
+        t.cbrt()
+    } else {
+        t / (3.0 * LAB_DELTA * LAB_DELTA) + 4.0 / 29.0
+    }
+}
+
+fn lab_f_inv(t: f32) -> f32 {
+    if t > LAB_DELTA { t * t * t } else { 3.0 * LAB_DELTA * LAB_DELTA * (t - 4.0 / 29.0) }
+}
+
+// L, a and b over 100.
+fn linear_to_lab(c: Color) -> Color {
+    let x = (0.4124564 * c[0] + 0.3575761 * c[1] + 0.1804375 * c[2]) / 0.95047;
+    let y = 0.2126729 * c[0] + 0.7151522 * c[1] + 0.0721750 * c[2];
+    let z = (0.0193339 * c[0] + 0.1191920 * c[1] + 0.9503041 * c[2]) / 1.08883;
+    let (fx, fy, fz) = (lab_f(x), lab_f(y), lab_f(z));
+    [1.16 * fy - 0.16, 5.0 * (fx - fy), 2.0 * (fy - fz)]
+}
+
+fn lab_to_linear(lab: Color) -> Color {
+    let fy = (lab[0] + 0.16) / 1.16;
+    let x = lab_f_inv(fy + lab[1] / 5.0) * 0.95047;
+    let y = lab_f_inv(fy);
+    let z = lab_f_inv(fy - lab[2] / 2.0) * 1.08883;
+    [
+        3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        -0.9692660 * x + 1.8760108 * y + 0.0415560 * z,
+        0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    ]
+}
+
+// https://bottosson.github.io/posts/oklab/
+fn linear_to_oklab(c: Color) -> Color {
+    let l = (0.4122214708 * c[0] + 0.5363325363 * c[1] + 0.0514459929 * c[2]).cbrt();
+    let m = (0.2119034982 * c[0] + 0.6806995451 * c[1] + 0.1073969566 * c[2]).cbrt();
+    let s = (0.0883024619 * c[0] + 0.2817188376 * c[1] + 0.6299787005 * c[2]).cbrt();
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+fn oklab_to_linear(lab: Color) -> Color {
+    let l = lab[0] + 0.3963377774 * lab[1] + 0.2158037573 * lab[2];
+    let m = lab[0] - 0.1055613458 * lab[1] - 0.0638541728 * lab[2];
+    let s = lab[0] - 0.0894841775 * lab[1] - 1.2914855480 * lab[2];
+    let (l, m, s) = (l * l * l, m * m * m, s * s * s);
+    [
+        4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+        -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+        -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
+    ]
+}
+
+// HSV as a cone: value is the height and saturation * value the distance from
+// the axis, so dark colors are close together whatever their hue.
+fn srgb_to_hsv(c: Color) -> Color {
+    let v = c[0].max(c[1]).max(c[2]);
+    let chroma = v - c[0].min(c[1]).min(c[2]);
+    let h = if chroma <= 0.0 {
+        0.0
+    } else if v == c[0] {
+        (c[1] - c[2]) / chroma
+    } else if v == c[1] {
+        (c[2] - c[0]) / chroma + 2.0
+    } else {
+        (c[0] - c[1]) / chroma + 4.0
+    };
+    let angle = h * ::std::f32::consts::PI / 3.0;
+    [chroma * angle.cos(), chroma * angle.sin(), v]
+}
+
+fn hsv_to_srgb(cone: Color) -> Color {
+    let v = cone[2];
+    let chroma = (cone[0] * cone[0] + cone[1] * cone[1]).sqrt();
+    let mut h = cone[1].atan2(cone[0]) * 3.0 / ::std::f32::consts::PI;
+    if h < 0.0 {
+        h += 6.0;
+    }
+    let x = chroma * (1.0 - ((h % 2.0) - 1.0).abs());
+    let rgb = if h < 1.0 {
+        [chroma, x, 0.0]
+    } else if h < 2.0 {
+        [x, chroma, 0.0]
+    } else if h < 3.0 {
+        [0.0, chroma, x]
+    } else if h < 4.0 {
+        [0.0, x, chroma]
+    } else if h < 5.0 {
+        [x, 0.0, chroma]
+    } else {
+        [chroma, 0.0, x]
+    };
+    // More chroma than value comes out negative, so out of gamut.
+    let m = v - chroma;
+    [rgb[0] + m, rgb[1] + m, rgb[2] + m]
+}
+
+pub fn to_space(srgb: Color, space: ColorSpace) -> Color {
+    match space {
+        ColorSpace::Srgb => srgb,
+        ColorSpace::Linear => {
+            let c = srgb_to_linear(srgb);
+            let scale = 3f32.sqrt();
+            [c[0] / scale, c[1] / scale, c[2] / scale]
+        },
+        ColorSpace::Lab => linear_to_lab(srgb_to_linear(srgb)),
+        ColorSpace::Oklab => linear_to_oklab(srgb_to_linear(srgb)),
+        ColorSpace::Hsv => srgb_to_hsv(srgb),
+    }
+}
+
+// Can be out of gamut.
+pub fn from_space(c: Color, space: ColorSpace) -> Color {
+    match space {
+        ColorSpace::Srgb => c,
+        ColorSpace::Linear => {
+            let scale = 3f32.sqrt();
+            linear_to_srgb([c[0] * scale, c[1] * scale, c[2] * scale])
+        },
+        ColorSpace::Lab => linear_to_srgb(lab_to_linear(c)),
+        ColorSpace::Oklab => linear_to_srgb(oklab_to_linear(c)),
+        ColorSpace::Hsv => hsv_to_srgb(c),
+    }
+}
+
+// Some slack for rounding on the way through the other spaces.
+pub fn in_gamut(srgb: Color) -> bool {
+    let slack = 1e-4;
+    srgb.iter().all(|&v| v >= -slack && v <= 1.0 + slack)
+}
+
+// How different are these colors? [0, 1] where 0 is most similar.
+// See color_distance in main.cl.
+pub fn distance(a: Color, b: Color, space: ColorSpace) -> f32 {
+    if let ColorSpace::Srgb = space {
+        return ((a[0] - b[0]).abs() + (a[1] - b[1]).abs() + (a[2] - b[2]).abs()) / 3.0;
+    }
+    let (a, b) = (to_space(a, space), to_space(b, space));
+    let d = (0..3).map(|i| (a[i] - b[i]).powi(2)).sum::<f32>().sqrt();
+    d.min(1.0)
+}
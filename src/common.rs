@@ -12,3 +12,17 @@ pub struct Cursor {
     pub pressed: bool,
 }
 
+
+// Messages to the sim loop.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Control {
+    // Start over.
+    Stop,
+    // Rebuild kernels from the .cl sources, keeping the canvas.
+    Reload,
+    // Start or stop saving frames.
+    ToggleRecord,
+}
+
+// The last kernel build error, if the current build failed.
+pub type BuildError = Option<String>;
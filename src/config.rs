@@ -0,0 +1,1044 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use rand;
+use rand::Rng;
+use std::fs;
+use std::iter;
+use std::path::PathBuf;
+use allcolors;
+use checkpoint;
+use life;
+use provenance;
+use mode::MODES;
+use seed;
+
+// Where the subject image comes from.
+#[derive(Clone, Debug)]
+pub enum Subject {
+    Camera,
+    Image(PathBuf),
+    // A directory of numbered images.
+    Frames(PathBuf),
+    Y4m(PathBuf),
+    // Headerless rgb24 frames at the canvas dims.
+    Raw(PathBuf),
+}
+
+impl Subject {
+    pub fn parse(s: &str) -> Subject {
+        if s == "cam" {
+            return Subject::Camera;
+        }
+        let path = PathBuf::from(s);
+        if path.is_dir() {
+            return Subject::Frames(path);
+        }
+        let ext = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+        match ext.as_ref().map(|ext| ext.as_str()) {
+            Some("y4m") => Subject::Y4m(path),
+            Some("rgb") | Some("raw") => Subject::Raw(path),
+            _ => Subject::Image(path),
+        }
+    }
+
+    // What to pass to --subject for this.
+    pub fn arg(&self) -> String {
+        match *self {
+            Subject::Camera => "cam".to_owned(),
+            Subject::Image(ref path) | Subject::Frames(ref path)
+                | Subject::Y4m(ref path) | Subject::Raw(ref path) => path.to_string_lossy().into_owned(),
+        }
+    }
+
+    // Whether frames arrive on their own schedule rather than the sim's.
+    pub fn is_live(&self) -> bool {
+        match *self {
+            Subject::Camera => true,
+            _ => false,
+        }
+    }
+}
+
+// How to fit subject frames that aren't the canvas size.
+#[derive(Clone, Copy, Debug)]
+pub enum Fit {
+    // Scale to the canvas, ignoring the aspect ratio.
+    Stretch,
+    // Scale to cover the canvas and cut off the edges.
+    Crop,
+    // Scale to fit inside the canvas and fill the bars with a color.
+    Letterbox([u8; 3]),
+    // Repeat at the original size.
+    Tile,
+}
+
+// Where pastiche's colors take their random steps and get compared.
+// The values are what main.cl gets, see COLOR_SPACE_* there.
+#[derive(Clone, Copy, Debug)]
+pub enum ColorSpace {
+    // The stored values as they are. Steps still stay in gamut rather than being clamped
+    // like they once were, so srgb runs don't match ones from before color spaces.
+    Srgb = 0,
+    Linear = 1,
+    Lab = 2,
+    Oklab = 3,
+    Hsv = 4,
+}
+
+impl ColorSpace {
+    pub fn parse(s: &str) -> ColorSpace {
+        match s {
+            "linear" => ColorSpace::Linear,
+            "lab" => ColorSpace::Lab,
+            "oklab" => ColorSpace::Oklab,
+            "hsv" => ColorSpace::Hsv,
+            _ => ColorSpace::Srgb,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ColorSpace::Srgb => "srgb",
+            ColorSpace::Linear => "linear",
+            ColorSpace::Lab => "lab",
+            ColorSpace::Oklab => "oklab",
+            ColorSpace::Hsv => "hsv",
+        }
+    }
+}
+
+// How a stencil image turns into where growth can go.
+#[derive(Clone, Copy, Debug)]
+pub enum StencilMode {
+    // Light pixels are open, dark ones are walls.
+    Threshold,
+    // Brightness is the chance a pixel fills whenever growth reaches it.
+    Probability,
+}
+
+impl StencilMode {
+    pub fn parse(s: &str) -> StencilMode {
+        match s {
+            "probability" => StencilMode::Probability,
+            _ => StencilMode::Threshold,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            StencilMode::Threshold => "threshold",
+            StencilMode::Probability => "probability",
+        }
+    }
+}
+
+// What kernels see past the edges of the canvas.
+// The values are what main.cl gets, see BOUNDARY_* there.
+#[derive(Clone, Copy, Debug)]
+pub enum Boundary {
+    // Nothing, off-canvas neighbors are skipped. The original behavior.
+    None = 0,
+    // The nearest edge pixel.
+    Clamp = 1,
+    // The opposite edge, as on a torus. Results tile seamlessly.
+    Wrap = 2,
+    // The canvas reflected at the edge. Tiles seamlessly when every other tile is flipped.
+    Mirror = 3,
+}
+
+impl Boundary {
+    pub fn parse(s: &str) -> Boundary {
+        match s {
+            "clamp" => Boundary::Clamp,
+            "wrap" => Boundary::Wrap,
+            "mirror" => Boundary::Mirror,
+            _ => Boundary::None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Boundary::None => "none",
+            Boundary::Clamp => "clamp",
+            Boundary::Wrap => "wrap",
+            Boundary::Mirror => "mirror",
+        }
+    }
+}
+
+// Which cells around a life cell count as its neighbors.
+// The values are what main.cl gets, see LIFE_* there.
+#[derive(Clone, Copy, Debug)]
+pub enum Neighborhood {
+    // The square around the cell.
+    Moore = 0,
+    // The diamond around the cell.
+    VonNeumann = 1,
+}
+
+// How often to save while recording.
+#[derive(Clone, Copy, Debug)]
+pub enum Cadence {
+    Frames(u64),
+    Millis(u64),
+}
+
+// What runs the growth step.
+#[derive(Clone, Copy, Debug)]
+pub enum BackendKind {
+    Gpu,
+    Cpu,
+}
+
+// Which kind of OpenCL device to look for.
+#[derive(Clone, Copy, Debug)]
+pub enum DeviceKind {
+    Gpu,
+    Cpu,
+    Accelerator,
+    All,
+}
+
+// How to pick an OpenCL device. Unset fields match anything.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceSelector {
+    // Substring of the platform name.
+    pub platform: Option<String>,
+    // Substring of the device name.
+    pub device: Option<String>,
+    // When unset, prefer gpus and fall back to cpus.
+    pub kind: Option<DeviceKind>,
+    // Index into the devices that match the rest of the selector.
+    pub index: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    // Run the simulation in a window.
+    Run,
+    // Run without a window or camera and write results to disk.
+    Headless {
+        // Stop after this many frames.
+        frames: Option<u64>,
+        // Stop once every pixel has been filled.
+        until_filled: bool,
+    },
+    // List OpenCL platforms and devices.
+    Devices,
+    // Turn recorded frames into an animation.
+    Export {
+        // Directory with result_NNNNNN.png frames.
+        frames: PathBuf,
+        out: PathBuf,
+    },
+    // Make a saved image again from the settings recorded in it.
+    // Runs like Headless for `frames` frames.
+    Replay {
+        png: PathBuf,
+        frames: u64,
+    },
+    // Run the pastiche kernel and its cpu port side by side and check they agree.
+    Compare {
+        frames: u64,
+        // How far apart a channel of the two canvases may be, out of 255.
+        tolerance: u8,
+    },
+}
+
+// How recorded frames become an animation.
+#[derive(Clone, Copy, Debug)]
+pub struct AnimOptions {
+    // How long each frame shows.
+    pub delay_ms: u32,
+    // 0 loops forever.
+    pub loops: u16,
+    // Drop this many frames between the ones that are kept.
+    pub skip: u32,
+}
+
+// Settings for the life mode.
+#[derive(Clone, Debug)]
+pub struct LifeOptions {
+    // B/S rule string. When unset, the pattern's rule or B3/S23.
+    pub rule: Option<String>,
+    pub neighborhood: Neighborhood,
+    pub radius: u32,
+    pub live: [u8; 3],
+    pub dead: [u8; 3],
+    // An RLE file to start from instead of random soup.
+    pub pattern: Option<PathBuf>,
+}
+
+// Everything about a run that used to be hardcoded.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub command: Command,
+    pub dims: (u32, u32),
+    pub subject: Subject,
+    // Take a new subject frame every this many frames.
+    pub subject_every: u64,
+    // Start recorded subjects over when they run out.
+    pub loop_subject: bool,
+    pub fit: Fit,
+    pub color_space: ColorSpace,
+    // How far each pastiche step moves a color, in the units of `color_space`.
+    pub color_distance: f32,
+    pub boundary: Boundary,
+    // Grow a texture that repeats without seams. Implies wrapping at the boundary.
+    pub tileable: bool,
+    // Keeps pastiche and allcolors growth inside a shape, see stencil.rs.
+    pub stencil: Option<PathBuf>,
+    pub stencil_mode: StencilMode,
+    pub backend: BackendKind,
+    pub device: DeviceSelector,
+    // Which simulation to run, one of mode::MODES.
+    pub mode: String,
+    // How many colors allcolors places each frame.
+    pub colors_per_frame: u32,
+    pub life: LifeOptions,
+    pub seed: u64,
+    // Advance the kernel clock by this much each frame instead of
+    // following the wall clock. Needed for runs to be reproducible.
+    pub frame_ms: Option<u32>,
+    // Whether to start out recording. Toggled with S in the window.
+    pub save_enabled: bool,
+    pub save_every: Cadence,
+    // Also save which pixels are filled.
+    pub save_mask: bool,
+    // Also save how far each pixel is from the subject.
+    pub save_score: bool,
+    // Also write recorded frames into this .gif or .png (APNG).
+    pub animation: Option<PathBuf>,
+    pub anim: AnimOptions,
+    // Stream frames as y4m to this file, or "-" for stdout.
+    pub video: Option<PathBuf>,
+    pub video_every: u64,
+    // Where to save checkpoints. Saved on exit, and every checkpoint_every frames if set.
+    pub checkpoint: Option<PathBuf>,
+    pub checkpoint_every: Option<u64>,
+    // Pick up a run from this checkpoint.
+    pub resume: Option<PathBuf>,
+    // Where saved images go.
+    pub out_dir: PathBuf,
+    // Seeding strategies for the growing modes, see seed.rs.
+    pub seeds: Vec<String>,
+    pub fake_mouse: bool,
+}
+
+// Parse "848x480" into (848, 480).
+fn parse_dims(s: &str) -> Result<(u32, u32), String> {
+    let parts: Vec<&str> = s.split('x').collect();
+    if parts.len() != 2 {
+        return Err(format!("dims must look like WIDTHxHEIGHT, got '{}'", s));
+    }
+    let w = parts[0].parse::<u32>().map_err(|e| format!("bad width '{}': {}", parts[0], e))?;
+    let h = parts[1].parse::<u32>().map_err(|e| format!("bad height '{}': {}", parts[1], e))?;
+    if w == 0 || h == 0 {
+        return Err(format!("dims must be nonzero, got '{}'", s));
+    }
+    Ok((w, h))
+}
+
+// Parse "ff8000" or "#ff8000" into [255, 128, 0].
+pub fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    let hex = s.trim_start_matches('#');
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(format!("colors must look like RRGGBB, got '{}'", s));
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i+2], 16)
+        .map_err(|e| format!("bad color '{}': {}", s, e));
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+// Parse "500" (frames), "250ms" or "2s".
+fn parse_cadence(s: &str) -> Result<Cadence, String> {
+    let cadence = if s.ends_with("ms") {
+        Cadence::Millis(parse_u64(&s[..s.len()-2])?)
+    } else if s.ends_with('s') {
+        Cadence::Millis(parse_u64(&s[..s.len()-1])? * 1000)
+    } else {
+        Cadence::Frames(parse_u64(s)?)
+    };
+    match cadence {
+        Cadence::Frames(0) | Cadence::Millis(0) => Err(format!("'{}' must be nonzero", s)),
+        cadence => Ok(cadence),
+    }
+}
+
+fn validate<T, F>(f: F) -> impl Fn(String) -> Result<(), String>
+    where F: Fn(&str) -> Result<T, String>
+{
+    move |s| f(&s).map(|_| ())
+}
+
+fn parse_life_radius(s: &str) -> Result<u32, String> {
+    match parse_u32(s)? {
+        0 => Err("the life radius must be at least 1".to_owned()),
+        r if r > 7 => Err(format!("the life radius goes up to 7, got {}", r)),
+        r => Ok(r),
+    }
+}
+
+fn parse_u64(s: &str) -> Result<u64, String> {
+    s.parse::<u64>().map_err(|e| format!("'{}': {}", s, e))
+}
+
+fn parse_u32(s: &str) -> Result<u32, String> {
+    s.parse::<u32>().map_err(|e| format!("'{}': {}", s, e))
+}
+
+fn parse_u16(s: &str) -> Result<u16, String> {
+    s.parse::<u16>().map_err(|e| format!("'{}': {}", s, e))
+}
+
+fn parse_u8(s: &str) -> Result<u8, String> {
+    s.parse::<u8>().map_err(|e| format!("'{}': {}", s, e))
+}
+
+fn parse_color_distance(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>().map_err(|e| format!("'{}': {}", s, e))? {
+        d if d > 0.0 && d.is_finite() => Ok(d),
+        d => Err(format!("the color distance must be positive, got {}", d)),
+    }
+}
+
+// Flags shared by every subcommand that runs the simulation.
+fn sim_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("dims")
+            .long("dims")
+            .value_name("WxH")
+            .default_value("848x480")
+            .validator(validate(parse_dims))
+            .help("Canvas dimensions"),
+        Arg::with_name("subject")
+            .long("subject")
+            .value_name("cam|PATH")
+            .default_value("cam")
+            .help("Subject source: the camera, an image, a directory of numbered frames, \
+                   a .y4m video, or a raw .rgb video (rgb24 at --dims)"),
+        Arg::with_name("subject-every")
+            .long("subject-every")
+            .value_name("FRAMES")
+            .default_value("10")
+            .validator(validate(parse_u64))
+            .help("How often to take a new subject frame"),
+        Arg::with_name("loop-subject")
+            .long("loop-subject")
+            .help("Start frame directories and videos over when they run out"),
+        Arg::with_name("fit")
+            .long("fit")
+            .value_name("POLICY")
+            .possible_values(&["stretch", "crop", "letterbox", "tile"])
+            .default_value("crop")
+            .help("How to fit subjects that aren't the canvas size"),
+        Arg::with_name("fill")
+            .long("fill")
+            .value_name("RRGGBB")
+            .default_value("000000")
+            .validator(validate(parse_color))
+            .help("Color of the bars with --fit letterbox"),
+        Arg::with_name("color-space")
+            .long("color-space")
+            .value_name("SPACE")
+            .possible_values(&["srgb", "linear", "lab", "oklab", "hsv"])
+            .default_value("srgb")
+            .help("Color space pastiche's colors wander in and allcolors compares in"),
+        Arg::with_name("color-distance")
+            .long("color-distance")
+            .value_name("DISTANCE")
+            .default_value("0.06")
+            .validator(validate(parse_color_distance))
+            .help("How far pastiche's colors wander each step, in the color space"),
+        Arg::with_name("boundary")
+            .long("boundary")
+            .value_name("EDGE")
+            .possible_values(&["none", "clamp", "wrap", "mirror"])
+            .default_value("none")
+            .help("What neighbors past the edge of the canvas are: nothing, the edge, \
+                   the opposite edge (tileable), or the canvas mirrored"),
+        Arg::with_name("tileable")
+            .long("tileable")
+            .help("Grow a seamless repeating texture: wrap at the edges, blend the subject \
+                   so it wraps too, and show the canvas 3x3 in the window"),
+        Arg::with_name("stencil")
+            .long("stencil")
+            .value_name("IMAGE")
+            .help("Only grow where IMAGE is light, for logos, letters and silhouettes. \
+                   Fit to the canvas like the subject, transparent pixels count as black"),
+        Arg::with_name("stencil-mode")
+            .long("stencil-mode")
+            .value_name("MODE")
+            .possible_values(&["threshold", "probability"])
+            .default_value("threshold")
+            .help("Whether the stencil is a wall where it's darker than half, or how \
+                   bright it is is the chance of growing there (allcolors only has walls)"),
+        Arg::with_name("backend")
+            .long("backend")
+            .value_name("gpu|cpu")
+            .possible_values(&["gpu", "cpu"])
+            .default_value("gpu")
+            .help("Run the kernel with OpenCL or with the (slow) Rust port"),
+        Arg::with_name("platform")
+            .long("platform")
+            .value_name("NAME")
+            .help("Use an OpenCL platform whose name contains NAME"),
+        Arg::with_name("device")
+            .long("device")
+            .value_name("NAME")
+            .help("Use an OpenCL device whose name contains NAME"),
+        Arg::with_name("device-type")
+            .long("device-type")
+            .value_name("TYPE")
+            .possible_values(&["gpu", "cpu", "accelerator", "all"])
+            .help("Kind of OpenCL device (default: gpu, falling back to cpu)"),
+        Arg::with_name("device-index")
+            .long("device-index")
+            .value_name("N")
+            .validator(validate(parse_u64))
+            .help("Pick the Nth matching device (see the devices command)"),
+        Arg::with_name("mode")
+            .long("mode")
+            .alias("kernel")
+            .value_name("MODE")
+            .possible_values(MODES)
+            .default_value("pastiche")
+            .help("Which simulation to run"),
+        Arg::with_name("colors-per-frame")
+            .long("colors-per-frame")
+            .value_name("N")
+            .default_value("1024")
+            .validator(validate(parse_u32))
+            .help("How many colors allcolors places between frames"),
+        Arg::with_name("life-rule")
+            .long("life-rule")
+            .value_name("RULE")
+            .validator(validate(life::Rule::parse))
+            .help("Life rule like B3/S23, or B2/S/C3 for Generations (default: the pattern's, or B3/S23)"),
+        Arg::with_name("life-neighborhood")
+            .long("life-neighborhood")
+            .value_name("SHAPE")
+            .possible_values(&["moore", "von-neumann"])
+            .default_value("moore")
+            .help("Which cells count as neighbors in life, unless the rule says"),
+        Arg::with_name("life-radius")
+            .long("life-radius")
+            .value_name("N")
+            .default_value("1")
+            .validator(validate(parse_life_radius))
+            .help("How far life neighborhoods reach"),
+        Arg::with_name("life-live")
+            .long("life-live")
+            .value_name("RRGGBB")
+            .default_value("00ffff")
+            .validator(validate(parse_color))
+            .help("Color of live cells. Dying cells fade to the dead color"),
+        Arg::with_name("life-dead")
+            .long("life-dead")
+            .value_name("RRGGBB")
+            .default_value("330000")
+            .validator(validate(parse_color))
+            .help("Color of dead cells"),
+        Arg::with_name("life-pattern")
+            .long("life-pattern")
+            .value_name("RLE")
+            .help("Start life from an RLE pattern in the middle instead of random soup"),
+        Arg::with_name("seed")
+            .long("seed")
+            .value_name("N")
+            .validator(validate(parse_u64))
+            .help("RNG seed (random if omitted)"),
+        Arg::with_name("frame-ms")
+            .long("frame-ms")
+            .value_name("MS")
+            .validator(validate(parse_u32))
+            .help("Advance the clock a fixed MS per frame instead of using wall time \
+                   (headless default: 16)"),
+        Arg::with_name("record")
+            .long("record")
+            .help("Save canvas images as result_NNNNNN.png in --out-dir (S toggles)"),
+        Arg::with_name("save-every")
+            .long("save-every")
+            .value_name("FRAMES|MSms|Ss")
+            .default_value("1000")
+            .validator(validate(parse_cadence))
+            .help("How often to save when recording, in frames or wall time (250ms, 2s)"),
+        Arg::with_name("record-mask")
+            .long("record-mask")
+            .help("Also save which pixels are filled as mask_NNNNNN.png"),
+        Arg::with_name("record-score")
+            .long("record-score")
+            .help("Also save the distance from the subject as score_NNNNNN.png"),
+        Arg::with_name("out-dir")
+            .long("out-dir")
+            .value_name("DIR")
+            .default_value(".")
+            .help("Directory for saved images"),
+        Arg::with_name("animation")
+            .long("animation")
+            .value_name("FILE")
+            .help("Also write recorded frames into an animated .gif or .png (APNG)"),
+        Arg::with_name("video")
+            .long("video")
+            .value_name("FILE|-")
+            .help("Stream frames as y4m video to FILE, or - for stdout (logs go to stderr)"),
+        Arg::with_name("video-every")
+            .long("video-every")
+            .value_name("FRAMES")
+            .default_value("1")
+            .validator(validate(parse_u64))
+            .help("How often to add a video frame"),
+        Arg::with_name("checkpoint")
+            .long("checkpoint")
+            .value_name("FILE")
+            .help("Save the whole run to FILE on exit, to pick it up later with --resume"),
+        Arg::with_name("checkpoint-every")
+            .long("checkpoint-every")
+            .value_name("FRAMES")
+            .requires("checkpoint")
+            .validator(validate(parse_u64))
+            .help("Also checkpoint every FRAMES frames"),
+        Arg::with_name("resume")
+            .long("resume")
+            .value_name("FILE")
+            .help("Continue the run saved in a checkpoint. Its settings replace \
+                   --mode, --dims, --seed, --subject and the rest of what the sim does"),
+        Arg::with_name("seeds")
+            .long("seeds")
+            .value_name("STRATEGY")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(validate(seed::parse))
+            .help("Where pastiche and allcolors seeds go, repeat to combine: center, grid, rain, \
+                   line, image or edges, with settings like rain:every=50,color=ff8000. \
+                   Defaults to center, center:dx=80 and rain. See seed.rs for the settings"),
+        // Before --seeds. Saved runs still have them.
+        Arg::with_name("no-drops")
+            .long("no-drops")
+            .hidden(true)
+            .conflicts_with("seeds"),
+        Arg::with_name("xmas-tree")
+            .long("xmas-tree")
+            .hidden(true)
+            .conflicts_with("seeds"),
+        Arg::with_name("fake-mouse")
+            .long("fake-mouse")
+            .help("Wander the cursor around like raindrops"),
+    ].into_iter().chain(anim_args()).collect()
+}
+
+// Flags for writing animations.
+fn anim_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("anim-delay")
+            .long("anim-delay")
+            .value_name("MS")
+            .default_value("50")
+            .validator(validate(parse_u32))
+            .help("How long each animation frame shows (gifs round to 10ms)"),
+        Arg::with_name("anim-loops")
+            .long("anim-loops")
+            .value_name("N")
+            .default_value("0")
+            .validator(validate(parse_u16))
+            .help("How many times the animation plays, 0 for forever"),
+        Arg::with_name("anim-skip")
+            .long("anim-skip")
+            .value_name("N")
+            .default_value("0")
+            .validator(validate(parse_u32))
+            .help("Keep one recorded frame, then drop N, for a faster and smaller animation"),
+    ]
+}
+
+fn anim_options(m: &ArgMatches) -> AnimOptions {
+    AnimOptions {
+        delay_ms: parse_u32(m.value_of("anim-delay").unwrap()).unwrap(),
+        loops: parse_u16(m.value_of("anim-loops").unwrap()).unwrap(),
+        skip: parse_u32(m.value_of("anim-skip").unwrap()).unwrap(),
+    }
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("pastiche")
+        .about("OpenCL doodling")
+        .setting(AppSettings::VersionlessSubcommands)
+        .args(&sim_args())
+        .subcommand(SubCommand::with_name("run")
+                    .about("Run the simulation in a window (default)")
+                    .args(&sim_args()))
+        .subcommand(SubCommand::with_name("headless")
+                    .about("Run without a window or camera, saving results to --out-dir")
+                    .args(&sim_args())
+                    .arg(Arg::with_name("frames")
+                         .long("frames")
+                         .value_name("N")
+                         .validator(validate(parse_u64))
+                         .required_unless("until-filled")
+                         .help("Stop after N frames, counting from the start of a resumed run"))
+                    .arg(Arg::with_name("until-filled")
+                         .long("until-filled")
+                         .help("Stop once the whole canvas is filled")))
+        .subcommand(SubCommand::with_name("replay")
+                    .about("Run again what made a saved result png, up to its frame, and check it comes out the same")
+                    .args(&sim_args())
+                    .arg(Arg::with_name("png")
+                         .value_name("PNG")
+                         .required(true)
+                         .help("A result png saved by pastiche")))
+        .subcommand(SubCommand::with_name("compare")
+                    .about("Run the pastiche kernel on OpenCL and on the cpu from the same start, \
+                            and check the canvases and masks agree")
+                    .args(&sim_args())
+                    .arg(Arg::with_name("frames")
+                         .long("frames")
+                         .value_name("N")
+                         .default_value("100")
+                         .validator(validate(parse_u64))
+                         .help("How many frames to run"))
+                    .arg(Arg::with_name("tolerance")
+                         .long("tolerance")
+                         .value_name("LEVELS")
+                         .default_value("2")
+                         .validator(validate(parse_u8))
+                         .help("How far apart a channel may be, out of 255")))
+        .subcommand(SubCommand::with_name("devices")
+                    .about("List OpenCL platforms and devices"))
+        .subcommand(SubCommand::with_name("export")
+                    .about("Turn recorded result_NNNNNN.png frames into an animated .gif or .png")
+                    .args(&anim_args())
+                    .arg(Arg::with_name("frames")
+                         .long("frames")
+                         .value_name("DIR")
+                         .default_value(".")
+                         .help("Directory the frames were recorded to"))
+                    .arg(Arg::with_name("out")
+                         .long("out")
+                         .value_name("FILE")
+                         .required(true)
+                         .help("The .gif, or .png for an APNG")))
+}
+
+fn sim_config(command: Command, m: &ArgMatches) -> Config {
+    let subject = Subject::parse(m.value_of("subject").unwrap());
+    let fit = match m.value_of("fit").unwrap() {
+        "stretch" => Fit::Stretch,
+        "letterbox" => Fit::Letterbox(parse_color(m.value_of("fill").unwrap()).unwrap()),
+        "tile" => Fit::Tile,
+        _ => Fit::Crop,
+    };
+    let backend = match m.value_of("backend").unwrap() {
+        "cpu" => BackendKind::Cpu,
+        _ => BackendKind::Gpu,
+    };
+    let device = DeviceSelector {
+        platform: m.value_of("platform").map(|s| s.to_owned()),
+        device: m.value_of("device").map(|s| s.to_owned()),
+        kind: m.value_of("device-type").map(|s| match s {
+            "cpu" => DeviceKind::Cpu,
+            "accelerator" => DeviceKind::Accelerator,
+            "all" => DeviceKind::All,
+            _ => DeviceKind::Gpu,
+        }),
+        index: m.value_of("device-index").map(|s| parse_u64(s).unwrap() as usize),
+    };
+    let tileable = m.is_present("tileable");
+    let boundary = match Boundary::parse(m.value_of("boundary").unwrap()) {
+        Boundary::Wrap => Boundary::Wrap,
+        _ if tileable && m.occurrences_of("boundary") > 0 => clap::Error::with_description(
+            "--tileable needs --boundary wrap",
+            clap::ErrorKind::ArgumentConflict).exit(),
+        _ if tileable => Boundary::Wrap,
+        other => other,
+    };
+    let seed = match m.value_of("seed") {
+        Some(s) => parse_u64(s).unwrap(),
+        None => rand::thread_rng().gen(),
+    };
+    let dims = parse_dims(m.value_of("dims").unwrap()).unwrap();
+    if m.value_of("mode") == Some("allcolors") && dims.0 as u64 * dims.1 as u64 > allcolors::MAX_PIXELS {
+        clap::Error::with_description(
+            &format!("allcolors uses each 24 bit color at most once, so {}x{} is too big (4096x4096 at most)",
+                     dims.0, dims.1),
+            clap::ErrorKind::InvalidValue).exit();
+    }
+    Config {
+        command: command,
+        dims: dims,
+        subject: subject,
+        subject_every: parse_u64(m.value_of("subject-every").unwrap()).unwrap().max(1),
+        loop_subject: m.is_present("loop-subject"),
+        fit: fit,
+        color_space: ColorSpace::parse(m.value_of("color-space").unwrap()),
+        color_distance: parse_color_distance(m.value_of("color-distance").unwrap()).unwrap(),
+        boundary: boundary,
+        tileable: tileable,
+        stencil: m.value_of("stencil").map(PathBuf::from),
+        stencil_mode: StencilMode::parse(m.value_of("stencil-mode").unwrap()),
+        backend: backend,
+        device: device,
+        mode: m.value_of("mode").unwrap().to_owned(),
+        colors_per_frame: parse_u32(m.value_of("colors-per-frame").unwrap()).unwrap(),
+        life: LifeOptions {
+            rule: m.value_of("life-rule").map(|s| s.to_owned()),
+            neighborhood: match m.value_of("life-neighborhood").unwrap() {
+                "von-neumann" => Neighborhood::VonNeumann,
+                _ => Neighborhood::Moore,
+            },
+            radius: parse_life_radius(m.value_of("life-radius").unwrap()).unwrap(),
+            live: parse_color(m.value_of("life-live").unwrap()).unwrap(),
+            dead: parse_color(m.value_of("life-dead").unwrap()).unwrap(),
+            pattern: m.value_of("life-pattern").map(PathBuf::from),
+        },
+        seed: seed,
+        frame_ms: m.value_of("frame-ms").map(|s| parse_u32(s).unwrap()),
+        save_enabled: m.is_present("record"),
+        save_every: parse_cadence(m.value_of("save-every").unwrap()).unwrap(),
+        save_mask: m.is_present("record-mask"),
+        save_score: m.is_present("record-score"),
+        animation: m.value_of("animation").map(PathBuf::from),
+        anim: anim_options(m),
+        video: m.value_of("video").map(PathBuf::from),
+        video_every: parse_u64(m.value_of("video-every").unwrap()).unwrap().max(1),
+        checkpoint: m.value_of("checkpoint").map(PathBuf::from),
+        checkpoint_every: m.value_of("checkpoint-every").map(|s| parse_u64(s).unwrap().max(1)),
+        resume: m.value_of("resume").map(PathBuf::from),
+        out_dir: PathBuf::from(m.value_of("out-dir").unwrap()),
+        seeds: seed_specs(m),
+        fake_mouse: m.is_present("fake-mouse"),
+    }
+}
+
+fn seed_specs(m: &ArgMatches) -> Vec<String> {
+    if let Some(specs) = m.values_of("seeds") {
+        return specs.map(|spec| spec.to_owned()).collect();
+    }
+    seed::DEFAULT.iter()
+        .filter(|&&spec| !(spec == "rain" && m.is_present("no-drops")))
+        .chain(if m.is_present("xmas-tree") { Some(&seed::XMAS_TREE) } else { None })
+        .map(|spec| spec.to_string())
+        .collect()
+}
+
+// The flags that decide what a run does, as opposed to where its output goes.
+// Saved alongside a run so it can be set up the same way again.
+pub fn sim_flags(config: &Config) -> Vec<String> {
+    let mut flags: Vec<String> = vec![
+        "--mode".to_owned(), config.mode.clone(),
+        "--dims".to_owned(), format!("{}x{}", config.dims.0, config.dims.1),
+        "--seed".to_owned(), config.seed.to_string(),
+        "--subject".to_owned(), config.subject.arg(),
+        "--subject-every".to_owned(), config.subject_every.to_string(),
+        "--colors-per-frame".to_owned(), config.colors_per_frame.to_string(),
+    ];
+    let fit = match config.fit {
+        Fit::Stretch => "stretch",
+        Fit::Crop => "crop",
+        Fit::Letterbox(fill) => {
+            flags.push("--fill".to_owned());
+            flags.push(format!("{:02x}{:02x}{:02x}", fill[0], fill[1], fill[2]));
+            "letterbox"
+        },
+        Fit::Tile => "tile",
+    };
+    flags.push("--fit".to_owned());
+    flags.push(fit.to_owned());
+    flags.push("--color-space".to_owned());
+    flags.push(config.color_space.name().to_owned());
+    flags.push("--color-distance".to_owned());
+    flags.push(config.color_distance.to_string());
+    flags.push("--boundary".to_owned());
+    flags.push(config.boundary.name().to_owned());
+    if let Some(ref stencil) = config.stencil {
+        flags.push("--stencil".to_owned());
+        flags.push(stencil.to_string_lossy().into_owned());
+        flags.push("--stencil-mode".to_owned());
+        flags.push(config.stencil_mode.name().to_owned());
+    }
+    if config.mode == "life" {
+        flags.extend(life_flags(&config.life));
+    }
+    for spec in config.seeds.iter() {
+        flags.push("--seeds".to_owned());
+        flags.push(spec.clone());
+    }
+    if let Some(frame_ms) = config.frame_ms {
+        flags.push("--frame-ms".to_owned());
+        flags.push(frame_ms.to_string());
+    }
+    let switches = [
+        (config.loop_subject, "--loop-subject"),
+        (config.fake_mouse, "--fake-mouse"),
+        (config.tileable, "--tileable"),
+    ];
+    for &(on, flag) in switches.iter() {
+        if on {
+            flags.push(flag.to_owned());
+        }
+    }
+    flags
+}
+
+fn life_flags(life: &LifeOptions) -> Vec<String> {
+    let hex = |c: [u8; 3]| format!("{:02x}{:02x}{:02x}", c[0], c[1], c[2]);
+    let neighborhood = match life.neighborhood {
+        Neighborhood::Moore => "moore",
+        Neighborhood::VonNeumann => "von-neumann",
+    };
+    let mut flags = vec![
+        "--life-neighborhood".to_owned(), neighborhood.to_owned(),
+        "--life-radius".to_owned(), life.radius.to_string(),
+        "--life-live".to_owned(), hex(life.live),
+        "--life-dead".to_owned(), hex(life.dead),
+    ];
+    if let Some(ref rule) = life.rule {
+        flags.push("--life-rule".to_owned());
+        flags.push(rule.clone());
+    }
+    if let Some(ref pattern) = life.pattern {
+        flags.push("--life-pattern".to_owned());
+        flags.push(pattern.to_string_lossy().into_owned());
+    }
+    flags
+}
+
+// Take what the sim does from flags saved by `sim_flags`, leaving the rest of `config` alone.
+pub fn apply_sim_flags(config: &mut Config, flags: &[String]) -> Result<(), String> {
+    let m = app()
+        .get_matches_from_safe(iter::once("pastiche".to_owned()).chain(flags.iter().cloned()))
+        .map_err(|err| err.message)?;
+    let saved = sim_config(Command::Run, &m);
+    config.mode = saved.mode;
+    config.dims = saved.dims;
+    config.seed = saved.seed;
+    config.subject = saved.subject;
+    config.subject_every = saved.subject_every;
+    config.loop_subject = saved.loop_subject;
+    config.fit = saved.fit;
+    config.color_space = saved.color_space;
+    config.color_distance = saved.color_distance;
+    config.boundary = saved.boundary;
+    config.tileable = saved.tileable;
+    config.stencil = saved.stencil;
+    config.stencil_mode = saved.stencil_mode;
+    config.colors_per_frame = saved.colors_per_frame;
+    config.life = saved.life;
+    config.frame_ms = saved.frame_ms;
+    config.seeds = saved.seeds;
+    config.fake_mouse = saved.fake_mouse;
+    Ok(())
+}
+
+fn apply_resume(config: &mut Config) {
+    if let Some(path) = config.resume.clone() {
+        let header = checkpoint::read_header(&path);
+        if let Err(err) = apply_sim_flags(config, &header.flags) {
+            clap::Error::with_description(
+                &format!("bad settings in checkpoint {}: {}", path.display(), err),
+                clap::ErrorKind::InvalidValue).exit();
+        }
+    }
+}
+
+pub fn from_args() -> Config {
+    let matches = app().get_matches();
+    match matches.subcommand() {
+        ("run", Some(m)) => {
+            let mut config = sim_config(Command::Run, m);
+            apply_resume(&mut config);
+            config
+        },
+        ("headless", Some(m)) => {
+            let command = Command::Headless {
+                frames: m.value_of("frames").map(|s| parse_u64(s).unwrap()),
+                until_filled: m.is_present("until-filled"),
+            };
+            let mut config = sim_config(command, m);
+            apply_resume(&mut config);
+            // Headless runs should come out the same every time.
+            if config.frame_ms.is_none() {
+                config.frame_ms = Some(16);
+            }
+            if let Subject::Camera = config.subject {
+                clap::Error::with_description(
+                    "headless runs need a subject file, pass --subject PATH",
+                    clap::ErrorKind::MissingRequiredArgument).exit();
+            }
+            config
+        },
+        ("replay", Some(m)) => {
+            let png = PathBuf::from(m.value_of("png").unwrap());
+            let entries = provenance::read(&png);
+            let fail = |what: &str| -> ! {
+                clap::Error::with_description(
+                    &format!("{} {}", png.display(), what),
+                    clap::ErrorKind::InvalidValue).exit()
+            };
+            let frame = provenance::get(&entries, provenance::FRAME)
+                .and_then(|frame| parse_u64(frame).ok())
+                .unwrap_or_else(|| fail("has no pastiche settings in it"));
+            let flags: Vec<String> = provenance::get(&entries, provenance::FLAGS)
+                .unwrap_or_else(|| fail("has no pastiche settings in it"))
+                .lines().map(|line| line.to_owned()).collect();
+            let command = Command::Replay {
+                png: png.clone(),
+                frames: frame + 1,
+            };
+            let mut config = sim_config(command, m);
+            if let Err(err) = apply_sim_flags(&mut config, &flags) {
+                fail(&format!("has bad settings: {}", err));
+            }
+            // Same as headless. A run on the wall clock can't be replayed exactly anyway.
+            if config.frame_ms.is_none() {
+                config.frame_ms = Some(16);
+            }
+            if let Subject::Camera = config.subject {
+                fail("was made from the camera, which can't be replayed");
+            }
+            // The replay goes to result.png, which mustn't be what it's checked against.
+            let result = fs::canonicalize(&config.out_dir).map(|dir| dir.join("result.png"));
+            if result.ok() == fs::canonicalize(&png).ok() {
+                fail("would be written over by the replay, pick another --out-dir");
+            }
+            config
+        },
+        ("compare", Some(m)) => {
+            let command = Command::Compare {
+                frames: parse_u64(m.value_of("frames").unwrap()).unwrap(),
+                tolerance: parse_u8(m.value_of("tolerance").unwrap()).unwrap(),
+            };
+            let mut config = sim_config(command, m);
+            // The clock goes into the kernel, so both sides need the same one.
+            if config.frame_ms.is_none() {
+                config.frame_ms = Some(16);
+            }
+            if config.mode != "pastiche" {
+                clap::Error::with_description(
+                    "only the pastiche kernel has a cpu port to compare with",
+                    clap::ErrorKind::InvalidValue).exit();
+            }
+            if let Subject::Camera = config.subject {
+                clap::Error::with_description(
+                    "compare needs a subject file, pass --subject PATH",
+                    clap::ErrorKind::MissingRequiredArgument).exit();
+            }
+            config
+        },
+        ("devices", Some(_)) => sim_config(Command::Devices, &matches),
+        ("export", Some(m)) => {
+            let command = Command::Export {
+                frames: PathBuf::from(m.value_of("frames").unwrap()),
+                out: PathBuf::from(m.value_of("out").unwrap()),
+            };
+            let mut config = sim_config(command, &matches);
+            config.anim = anim_options(m);
+            config
+        },
+        _ => {
+            let mut config = sim_config(Command::Run, &matches);
+            apply_resume(&mut config);
+            config
+        },
+    }
+}
@@ -0,0 +1,435 @@
+use image;
+use rayon::prelude::*;
+use backend::{Backend, Canvas, FloatCanvas, Mask, MaskVal, StepParams};
+use backend::{float_canvas, tone_map, unorm};
+use cam;
+use checkpoint;
+use common::Cursor;
+use color;
+use config::{BackendKind, Boundary, ColorSpace, Config};
+use mode;
+use mode::Mode;
+use sim;
+use stencil;
+use stencil::Stencil;
+
+// A plain Rust port of the `pastiche` kernel in main.cl.
+// Slow, but it runs anywhere and it's something to check the kernel against.
+// Keep the two in sync: same rand draws in the same order, same constants.
+// `pastiche compare` runs both and reports where they part, and the tests
+// below pin the math they share.
+
+type Rgba = [f32; 4];
+
+// SplitMix64, same as main.cl.
+const RAND_GAMMA: u64 = 0x9E3779B97F4A7C15;
+
+// Same order as `neighbor_deltas` in main.cl.
+const NEIGHBOR_DELTAS: [(i32, i32); 8] = [
+    (-1, -1),
+    (-1, 0),
+    (-1, 1),
+    (0, -1),
+    // skip 0, 0
+    (0, 1),
+    (1, -1),
+    (1, 0),
+    (1, 1),
+];
+
+fn rand_mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+fn rand_uint(state: &mut u64) -> u32 {
+    *state = state.wrapping_add(RAND_GAMMA);
+    (rand_mix(*state) >> 32) as u32
+}
+
+// [0, 1)
+fn rand_float(state: &mut u64) -> f32 {
+    (rand_uint(state) >> 8) as f32 * (1.0 / 16777216.0) // 2**24
+}
+
+// Same starting counters as the `rand_seed` kernel.
+fn rand_seed(seed: u64, npixels: usize) -> Vec<u64> {
+    (0..npixels as u64).map(|id| rand_mix(seed.wrapping_add(id.wrapping_mul(RAND_GAMMA)))).collect()
+}
+
+fn distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+// Same as `boundary_distance` in main.cl.
+fn boundary_distance(a: (f32, f32), b: (f32, f32), dims: (u32, u32), boundary: Boundary) -> f32 {
+    let (mut dx, mut dy) = ((a.0 - b.0).abs(), (a.1 - b.1).abs());
+    if let Boundary::Wrap = boundary {
+        dx = dx.min(dims.0 as f32 - dx);
+        dy = dy.min(dims.1 as f32 - dy);
+    }
+    (dx * dx + dy * dy).sqrt()
+}
+
+// A random direction, uniform over the sphere, like `random_direction` in main.cl.
+fn random_direction(rng: &mut u64) -> [f32; 3] {
+    let mut v = [1.0, 0.0, 0.0];
+    for _ in 0..8 {
+        let dx = rand_float(rng) - 0.5;
+        let dy = rand_float(rng) - 0.5;
+        let dz = rand_float(rng) - 0.5;
+        v = [dx, dy, dz];
+        let len = (dx * dx + dy * dy + dz * dz).sqrt();
+        if len > 1e-3 && len <= 0.5 {
+            break;
+        }
+    }
+    let len = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    if len > 1e-3 { [v[0] / len, v[1] / len, v[2] / len] } else { [1.0, 0.0, 0.0] }
+}
+
+const COLOR_STEP_TRIES: usize = 16;
+
+// Find a new color that is `d` away from `rgba1` in `space`, or less when
+// boxed into a corner of the gamut. d is [0, 1] where 0 is most similar.
+fn color_at_distance(rgba1: Rgba, d: f32, rng: &mut u64, space: ColorSpace) -> Rgba {
+    let from = color::to_space([rgba1[0], rgba1[1], rgba1[2]], space);
+    let step = |dir: [f32; 3], len: f32| {
+        color::from_space([from[0] + dir[0] * len, from[1] + dir[1] * len, from[2] + dir[2] * len], space)
+    };
+    let done = |srgb: [f32; 3]| {
+        [srgb[0].max(0.0).min(1.0), srgb[1].max(0.0).min(1.0), srgb[2].max(0.0).min(1.0), rgba1[3]]
+    };
+    let mut dir = [1.0, 0.0, 0.0];
+    for _ in 0..COLOR_STEP_TRIES {
+        dir = random_direction(rng);
+        let srgb = step(dir, d);
+        if color::in_gamut(srgb) {
+            return done(srgb);
+        }
+    }
+    // Boxed into a corner. Go as far along the last direction as the gamut allows.
+    let (mut lo, mut hi) = (0.0, d);
+    for _ in 0..12 {
+        let mid = (lo + hi) / 2.0;
+        if color::in_gamut(step(dir, mid)) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    done(step(dir, lo))
+}
+
+// Everything a pixel can look at during a step.
+struct StepInput<'a> {
+    dims: (u32, u32),
+    canvas: &'a FloatCanvas,
+    mask: &'a Mask,
+    subject: &'a Canvas,
+    stencil: &'a Stencil,
+    time_ms: u32,
+    cursor: &'a Cursor,
+    color_space: ColorSpace,
+    color_distance: f32,
+    boundary: Boundary,
+}
+
+// One pixel of the `pastiche` kernel.
+fn pastiche_pixel(x: u32, y: u32, input: &StepInput, rng: &mut u64) -> (Rgba, MaskVal) {
+    let pixel = (x as f32, y as f32);
+    let cursor = input.cursor;
+    let cursor_xy = (cursor.x as f32, cursor.y as f32);
+
+    // Cursor pressed
+    if cursor.enabled && cursor.pressed {
+        let distance_to_cursor = boundary_distance(pixel, cursor_xy, input.dims, input.boundary);
+        if input.time_ms < 30000 {
+            if distance_to_cursor < 20.0 {
+                let mask = if distance_to_cursor < 5.0 { 1 } else { 0 };
+                return ([0.667, 0.0, 0.0, 1.0], mask);
+            }
+        } else {
+            if distance_to_cursor < 2.0 {
+                return ([1.0, 1.0, 1.0, 1.0], 1);
+            }
+        }
+    }
+
+    let src_rgba = input.canvas.get_pixel(x, y).0;
+    let mask_self = input.mask.get_pixel(x, y)[0];
+
+    // Slow it all down. Causes growth in a fuzzy circle rather than a strict square.
+    if rand_float(rng) < 0.55 {
+        return (src_rgba, mask_self);
+    }
+
+    let mut max_neighbor_mask: MaskVal = 0;
+    let mut selected_neighbor_rgba: Rgba = [1.0, 0.0, 1.0, 1.0];
+    let neighbor_index_offset = (rand_uint(rng) % 8) as usize;
+    for i in 0..8 {
+        let (dx, dy) = NEIGHBOR_DELTAS[(i + neighbor_index_offset) % 8];
+        if let Some((nx, ny)) = sim::in_bounds(input.boundary, input.dims, x as i32 + dx, y as i32 + dy) {
+            let mask_neighbor = input.mask.get_pixel(nx, ny)[0];
+            if mask_neighbor > max_neighbor_mask {
+                max_neighbor_mask = mask_neighbor;
+                selected_neighbor_rgba = input.canvas.get_pixel(nx, ny).0;
+            }
+        }
+    }
+
+    if max_neighbor_mask == 0 {
+        // No interesting neighbors.
+        return (src_rgba, mask_self);
+    }
+    if mask_self >= max_neighbor_mask {
+        // This spot is filled with the latest nearby generation already.
+        return (src_rgba, mask_self);
+    }
+
+    // Walled off by the stencil, or not this time.
+    let open = input.stencil.get_pixel(x, y)[0] as f32 / 255.0;
+    if open < 1.0 && (open <= 0.0 || rand_float(rng) >= open) {
+        return (src_rgba, mask_self);
+    }
+
+    let mut out_canvas_rgba = color_at_distance(selected_neighbor_rgba, input.color_distance, rng, input.color_space);
+
+    let diagonal = distance((0.0, 0.0), (input.dims.0 as f32, input.dims.1 as f32));
+    let distance_to_cursor_normed = boundary_distance(pixel, cursor_xy, input.dims, input.boundary) / diagonal;
+    let subject_chance = 0.3 - distance_to_cursor_normed;
+    if rand_float(rng) < subject_chance {
+        out_canvas_rgba = unorm(input.subject.get_pixel(x, y));
+    }
+
+    (out_canvas_rgba, max_neighbor_mask)
+}
+
+pub struct CpuBackend {
+    dims: (u32, u32),
+    color_space: ColorSpace,
+    color_distance: f32,
+    boundary: Boundary,
+    canvas: FloatCanvas,
+    mask: Mask,
+    subject: Canvas,
+    stencil: Stencil,
+    // Destination buffers, swapped with the above after each step.
+    next_canvas: FloatCanvas,
+    next_mask: Mask,
+    // Per-pixel rng counters, like the gpu's rng_state buffer.
+    rng_state: Vec<u64>,
+}
+
+impl CpuBackend {
+    pub fn new(config: &Config, canvas: Canvas, mask: Mask, subject: &Canvas) -> CpuBackend {
+        let dims = config.dims;
+        let canvas = float_canvas(&canvas);
+        CpuBackend {
+            dims: dims,
+            color_space: config.color_space,
+            color_distance: config.color_distance,
+            boundary: config.boundary,
+            next_canvas: canvas.clone(),
+            next_mask: mask.clone(),
+            canvas: canvas,
+            mask: mask,
+            subject: subject.clone(),
+            stencil: stencil::open(dims),
+            rng_state: rand_seed(config.seed, (dims.0 * dims.1) as usize),
+        }
+    }
+}
+
+impl Backend for CpuBackend {
+    fn describe(&self) -> String {
+        format!("cpu ({} threads)", ::rayon::current_num_threads())
+    }
+
+    fn set_subject(&mut self, subject: &Canvas) {
+        self.subject = subject.clone();
+    }
+
+    fn set_stencil(&mut self, stencil: &Stencil) {
+        self.stencil = stencil.clone();
+    }
+
+    fn place_pixel(&mut self, x: u32, y: u32, color: image::Rgba<u8>, mask_value: MaskVal) {
+        self.canvas.put_pixel(x, y, image::Rgba(unorm(&color)));
+        self.mask.put_pixel(x, y, image::Luma([mask_value]));
+    }
+
+    fn step(&mut self, params: &StepParams) {
+        let width = self.dims.0 as usize;
+        let input = StepInput {
+            dims: self.dims,
+            canvas: &self.canvas,
+            mask: &self.mask,
+            subject: &self.subject,
+            stencil: &self.stencil,
+            time_ms: params.time_ms,
+            cursor: &params.cursor,
+            color_space: self.color_space,
+            color_distance: self.color_distance,
+            boundary: self.boundary,
+        };
+        let out_canvas: &mut [f32] = &mut self.next_canvas;
+        let out_mask: &mut [MaskVal] = &mut self.next_mask;
+        out_canvas.par_chunks_mut(width * 4)
+            .zip(out_mask.par_chunks_mut(width))
+            .zip(self.rng_state.par_chunks_mut(width))
+            .enumerate()
+            .for_each(|(y, ((canvas_row, mask_row), rng_row))| {
+                for x in 0..width {
+                    let (rgba, mask) = pastiche_pixel(x as u32, y as u32, &input, &mut rng_row[x]);
+                    canvas_row[x * 4..x * 4 + 4].copy_from_slice(&rgba);
+                    mask_row[x] = mask;
+                }
+            });
+
+        ::std::mem::swap(&mut self.canvas, &mut self.next_canvas);
+        ::std::mem::swap(&mut self.mask, &mut self.next_mask);
+    }
+
+    fn read_canvas(&mut self, canvas: &mut Canvas) {
+        tone_map(&self.canvas, canvas);
+    }
+
+    fn read_mask(&mut self, mask: &mut Mask) {
+        mask.clone_from(&self.mask);
+    }
+
+    // The full precision canvas, then the rng counters.
+    fn read_state(&mut self) -> Vec<u8> {
+        let mut state = checkpoint::f32s_to_bytes(&self.canvas);
+        state.extend(checkpoint::u64s_to_bytes(&self.rng_state));
+        state
+    }
+
+    fn write_state(&mut self, state: &[u8]) {
+        let (canvas, rng_state) = checkpoint::split_canvas_state(state, self.dims);
+        self.canvas.copy_from_slice(&canvas);
+        self.rng_state = checkpoint::u64s_from_bytes(rng_state);
+    }
+}
+
+// Run the kernel and this port side by side from the same start, with no cursor,
+// and stop at the first frame their canvases or masks disagree.
+// Only the subject's first frame is used. True if they agree all the way.
+pub fn compare(config: &Config, frames: u64, tolerance: u8) -> bool {
+    let dims = config.dims;
+    let mut source = cam::open(&config.subject, dims);
+    let first = source.next_frame().unwrap_or_else(|| panic!("subject has no frames: {}", source.describe()));
+    let first = cam::fit(first, dims, config.fit);
+    let subject = cam::convert(if config.tileable { cam::seamless(&first) } else { first });
+
+    let mut sides: Vec<(Box<dyn Mode>, Box<dyn Backend>)> = [BackendKind::Gpu, BackendKind::Cpu].iter()
+        .map(|&kind| {
+            let mut config = config.clone();
+            config.backend = kind;
+            let mut mode = mode::create(&config);
+            let canvas = mode.initial_canvas(&config, &subject);
+            let mut backend = mode.create_backend(&config, canvas, mode.initial_mask(&config), &subject);
+            printlnc!(white_bold: "backend: {}", backend.describe());
+            mode.start(&mut *backend, &subject);
+            (mode, backend)
+        })
+        .collect();
+
+    let mut canvases = [Canvas::new(dims.0, dims.1), Canvas::new(dims.0, dims.1)];
+    let mut masks = [Mask::new(dims.0, dims.1), Mask::new(dims.0, dims.1)];
+    let frame_ms = config.frame_ms.unwrap_or(16);
+    for frame in 0..frames {
+        let params = StepParams {
+            time_ms: (frame as u32).wrapping_mul(frame_ms),
+            cursor: Default::default(),
+        };
+        for (i, &mut (ref mut mode, ref mut backend)) in sides.iter_mut().enumerate() {
+            backend.step(&params);
+            mode.after_step(frame, &mut **backend, &subject, &params.cursor, &mut sim::frame_rng(config.seed, frame));
+            backend.read_canvas(&mut canvases[i]);
+            backend.read_mask(&mut masks[i]);
+        }
+
+        let colors = canvases[0].pixels().zip(canvases[1].pixels())
+            .filter(|&(a, b)| (0..4).any(|c| (a[c] as i32 - b[c] as i32).abs() > tolerance as i32))
+            .count();
+        let masks_off = masks[0].pixels().zip(masks[1].pixels()).filter(|&(a, b)| a != b).count();
+        if colors > 0 || masks_off > 0 {
+            printlnc!(red: "frame {}: {} pixels differ by more than {}, {} mask values differ",
+                      frame, colors, tolerance, masks_off);
+            return false;
+        }
+    }
+    printlnc!(green: "the gpu and cpu agree for {} frames", frames);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step_length(a: Rgba, b: Rgba) -> f32 {
+        ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+    }
+
+    // The first outputs of SplitMix64 started at 0, as published with it.
+    #[test]
+    fn rand_is_splitmix64() {
+        let mut state: u64 = 0;
+        let outputs: Vec<u64> = (0..3).map(|_| {
+            state = state.wrapping_add(RAND_GAMMA);
+            rand_mix(state)
+        }).collect();
+        assert_eq!(outputs, vec![0xE220A8397B1DCDAF, 0x6E789E6AA1B965F4, 0x06C45D188009454F]);
+
+        let mut state = 0;
+        assert_eq!(rand_uint(&mut state), 0xE220A839);
+        assert_eq!(rand_float(&mut state), (0x6E789E6A >> 8) as f32 / 16777216.0);
+    }
+
+    // Pixel `id` starts at rand_mix(seed + id * RAND_GAMMA), like the rand_seed kernel.
+    #[test]
+    fn rand_seed_counters() {
+        assert_eq!(rand_seed(0, 3), vec![0, 0xE220A8397B1DCDAF, 0x6E789E6AA1B965F4]);
+        let seeded = rand_seed(1234, 4);
+        assert_eq!(seeded[3], rand_mix(1234u64.wrapping_add(3u64.wrapping_mul(RAND_GAMMA))));
+    }
+
+    #[test]
+    fn color_at_distance_pinned() {
+        let mut rng = 7;
+        let out = color_at_distance([0.5, 0.5, 0.5, 0.75], 0.06, &mut rng, ColorSpace::Srgb);
+        let expected = [0.5185536, 0.48936003, 0.4439415, 0.75];
+        for c in 0..4 {
+            assert!((out[c] - expected[c]).abs() < 1e-6, "{:?} != {:?}", out, expected);
+        }
+        // Two tries at a direction, three draws each. The kernel must draw as many.
+        assert_eq!(rng, 7u64.wrapping_add(6u64.wrapping_mul(RAND_GAMMA)));
+    }
+
+    // Away from the edges of the gamut a step is the full distance.
+    #[test]
+    fn color_at_distance_full_step() {
+        for seed in 0..100 {
+            let mut rng = rand_mix(seed);
+            let from = [0.5, 0.4, 0.6, 1.0];
+            let out = color_at_distance(from, 0.06, &mut rng, ColorSpace::Srgb);
+            assert!((step_length(from, out) - 0.06).abs() < 1e-5, "{:?}", out);
+            assert_eq!(out[3], 1.0);
+        }
+    }
+
+    // In a corner most directions leave the gamut, and the step may come up short.
+    #[test]
+    fn color_at_distance_corner() {
+        for &space in [ColorSpace::Srgb, ColorSpace::Linear, ColorSpace::Lab, ColorSpace::Oklab, ColorSpace::Hsv].iter() {
+            for seed in 0..100 {
+                let mut rng = rand_mix(seed);
+                let out = color_at_distance([1.0, 1.0, 1.0, 1.0], 0.3, &mut rng, space);
+                assert!(out.iter().all(|v| (0.0..=1.0).contains(v)), "{:?} in {:?}", out, space);
+            }
+        }
+    }
+}
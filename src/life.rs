@@ -0,0 +1,304 @@
+use std::fs;
+use std::path::Path;
+use backend::MaskVal;
+use config::Neighborhood;
+
+// Life-like rules for the life kernel in main.cl.
+//
+// Cells live in the mask: 0 is dead, 1 alive, and with more than 2 states
+// (Generations rules) a cell that doesn't survive counts up through the dying
+// states back to 0. Dying cells don't count as neighbors and can't be born into.
+
+// Neighbor counts go in 128 bit sets, as four ints for the kernel.
+pub const MAX_NEIGHBORS: u32 = 127;
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    // Bit n is set when n live neighbors bring a dead cell to life.
+    pub birth: [u32; 4],
+    // Bit n is set when a live cell with n live neighbors stays alive.
+    pub survive: [u32; 4],
+    // 2 for plain life.
+    pub states: u32,
+    // When the rule says, like Golly's V suffix.
+    pub neighborhood: Option<Neighborhood>,
+}
+
+impl Rule {
+    // B3/S23 notation, or Golly's S/B: "B36/S23", "23/36", "B3/S23V" for von Neumann.
+    // Generations rules take a third part with the number of states: "B2/S/C3" or "345/2/4".
+    // Counts are single digits, or for bigger neighborhoods a comma separated
+    // list with ranges: "B34-45/S33-57".
+    pub fn parse(s: &str) -> Result<Rule, String> {
+        let bad = |why: &str| format!("bad life rule '{}': {}", s, why);
+        let mut body = s.trim();
+        let mut neighborhood = None;
+        if body.ends_with('V') || body.ends_with('v') {
+            neighborhood = Some(Neighborhood::VonNeumann);
+            body = &body[..body.len() - 1];
+        } else if body.ends_with('M') || body.ends_with('m') {
+            neighborhood = Some(Neighborhood::Moore);
+            body = &body[..body.len() - 1];
+        }
+
+        let parts: Vec<&str> = body.split('/').collect();
+        if parts.len() < 2 || parts.len() > 3 {
+            return Err(bad("expected B.../S... or S/B"));
+        }
+        let (mut birth, mut survive, mut states) = (None, None, None);
+        let lettered = parts.iter().any(|part| part.starts_with(|c: char| c.is_ascii_alphabetic()));
+        for (i, part) in parts.iter().enumerate() {
+            let (kind, counts) = if lettered {
+                let mut chars = part.chars();
+                match chars.next().map(|c| c.to_ascii_uppercase()) {
+                    Some(kind) => (kind, chars.as_str()),
+                    None => return Err(bad("empty part")),
+                }
+            } else {
+                (['S', 'B', 'C'][i], *part)
+            };
+            match kind {
+                'B' if birth.is_none() => birth = Some(parse_counts(counts).map_err(|e| bad(&e))?),
+                'S' if survive.is_none() => survive = Some(parse_counts(counts).map_err(|e| bad(&e))?),
+                'C' | 'G' if states.is_none() => {
+                    let n = counts.parse::<u32>().map_err(|e| bad(&format!("states '{}': {}", counts, e)))?;
+                    if n < 2 || n > 256 {
+                        return Err(bad("states must be 2 to 256"));
+                    }
+                    states = Some(n);
+                },
+                _ => return Err(bad(&format!("unexpected '{}'", part))),
+            }
+        }
+        match (birth, survive) {
+            (Some(birth), Some(survive)) => Ok(Rule {
+                birth: birth,
+                survive: survive,
+                states: states.unwrap_or(2),
+                neighborhood: neighborhood,
+            }),
+            _ => Err(bad("needs both B and S")),
+        }
+    }
+}
+
+// "23" or "2,3,10-12".
+fn parse_counts(s: &str) -> Result<[u32; 4], String> {
+    let mut set = [0u32; 4];
+    let mut add = |n: u32| -> Result<(), String> {
+        if n > MAX_NEIGHBORS {
+            return Err(format!("counts go up to {}, got {}", MAX_NEIGHBORS, n));
+        }
+        set[(n / 32) as usize] |= 1 << (n % 32);
+        Ok(())
+    };
+    let number = |s: &str| s.trim().parse::<u32>().map_err(|e| format!("count '{}': {}", s, e));
+    if s.contains(',') || s.contains('-') {
+        for item in s.split(',').filter(|item| !item.trim().is_empty()) {
+            let mut ends = item.splitn(2, '-');
+            let lo = number(ends.next().unwrap())?;
+            let hi = match ends.next() {
+                Some(hi) => number(hi)?,
+                None => lo,
+            };
+            for n in lo..hi + 1 {
+                add(n)?;
+            }
+        }
+    } else {
+        for c in s.chars() {
+            match c.to_digit(10) {
+                Some(n) => add(n)?,
+                None => return Err(format!("count '{}' isn't a digit", c)),
+            }
+        }
+    }
+    Ok(set)
+}
+
+// How many cells a neighborhood of `radius` looks at, not counting the middle.
+pub fn neighbor_count(neighborhood: Neighborhood, radius: u32) -> u32 {
+    match neighborhood {
+        Neighborhood::Moore => (2 * radius + 1) * (2 * radius + 1) - 1,
+        Neighborhood::VonNeumann => 2 * radius * (radius + 1),
+    }
+}
+
+// A starting pattern from an RLE file, as written by Golly and the LifeWiki.
+// http://www.conwaylife.com/wiki/Run_Length_Encoded
+pub struct Pattern {
+    pub width: u32,
+    pub height: u32,
+    // From the header line, if it has one.
+    pub rule: Option<String>,
+    // Cells that aren't dead, with their state.
+    pub cells: Vec<(u32, u32, MaskVal)>,
+}
+
+pub fn load_rle(path: &Path) -> Result<Pattern, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("read {}: {}", path.display(), err))?;
+    parse_rle(&text).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+fn parse_rle(text: &str) -> Result<Pattern, String> {
+    let mut size = None;
+    let mut rule = None;
+    let mut body = String::new();
+    for line in text.lines().map(|line| line.trim()) {
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        if body.is_empty() && size.is_none() && line.starts_with('x') {
+            // x = 3, y = 3, rule = B3/S23
+            let (mut w, mut h) = (0, 0);
+            for field in line.split(',') {
+                let mut kv = field.splitn(2, '=');
+                let key = kv.next().unwrap_or("").trim();
+                let value = kv.next().unwrap_or("").trim();
+                match key {
+                    "x" => w = value.parse::<u32>().map_err(|e| format!("bad x '{}': {}", value, e))?,
+                    "y" => h = value.parse::<u32>().map_err(|e| format!("bad y '{}': {}", value, e))?,
+                    "rule" => rule = Some(value.to_owned()),
+                    _ => {},
+                }
+            }
+            size = Some((w, h));
+            continue;
+        }
+        body.push_str(line);
+    }
+
+    let mut cells = Vec::new();
+    let (mut x, mut y) = (0u32, 0u32);
+    let mut run = 0u32;
+    // Multi-state files spell states past 24 with a prefix, pA is 25.
+    let mut prefix = 0u32;
+    for c in body.chars() {
+        let count = run.max(1);
+        match c {
+            '0'..='9' => {
+                run = run * 10 + c.to_digit(10).unwrap();
+                continue;
+            },
+            'b' | '.' => x += count,
+            '$' => {
+                y += count;
+                x = 0;
+            },
+            '!' => break,
+            'p'..='y' => {
+                prefix = c as u32 - 'p' as u32 + 1;
+                continue;
+            },
+            'o' | 'A'..='X' => {
+                let state = if c == 'o' { 1 } else { prefix * 24 + (c as u32 - 'A' as u32) + 1 };
+                for i in 0..count {
+                    cells.push((x + i, y, state));
+                }
+                x += count;
+            },
+            c if c.is_whitespace() => continue,
+            other => return Err(format!("unexpected '{}' in the pattern", other)),
+        }
+        run = 0;
+        prefix = 0;
+    }
+
+    let (width, height) = match size {
+        Some(size) => size,
+        None => (
+            cells.iter().map(|&(x, _, _)| x + 1).max().unwrap_or(0),
+            cells.iter().map(|&(_, y, _)| y + 1).max().unwrap_or(0),
+        ),
+    };
+    Ok(Pattern {
+        width: width,
+        height: height,
+        rule: rule,
+        cells: cells,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(set: [u32; 4]) -> Vec<u32> {
+        (0..MAX_NEIGHBORS + 1).filter(|&n| set[(n / 32) as usize] & (1 << (n % 32)) != 0).collect()
+    }
+
+    #[test]
+    fn b_s_and_s_b_agree() {
+        let bs = Rule::parse("B3/S23").unwrap();
+        let sb = Rule::parse("23/3").unwrap();
+        assert_eq!(counts(bs.birth), vec![3]);
+        assert_eq!(counts(bs.survive), vec![2, 3]);
+        assert_eq!(bs.birth, sb.birth);
+        assert_eq!(bs.survive, sb.survive);
+        assert_eq!((bs.states, sb.states), (2, 2));
+        assert!(bs.neighborhood.is_none());
+        // Either order, any case.
+        assert_eq!(Rule::parse("s23/b3").unwrap().birth, bs.birth);
+    }
+
+    #[test]
+    fn generations() {
+        let brain = Rule::parse("B2/S/C3").unwrap();
+        assert_eq!(counts(brain.birth), vec![2]);
+        assert!(counts(brain.survive).is_empty());
+        assert_eq!(brain.states, 3);
+        let star_wars = Rule::parse("345/2/4").unwrap();
+        assert_eq!(counts(star_wars.survive), vec![3, 4, 5]);
+        assert_eq!(counts(star_wars.birth), vec![2]);
+        assert_eq!(star_wars.states, 4);
+    }
+
+    #[test]
+    fn neighborhood_suffix() {
+        match Rule::parse("B3/S23V").unwrap().neighborhood {
+            Some(Neighborhood::VonNeumann) => {},
+            other => panic!("{:?}", other),
+        }
+        match Rule::parse("B3/S23m").unwrap().neighborhood {
+            Some(Neighborhood::Moore) => {},
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let rule = Rule::parse("B34-36/S33-35,40").unwrap();
+        assert_eq!(counts(rule.birth), vec![34, 35, 36]);
+        assert_eq!(counts(rule.survive), vec![33, 34, 35, 40]);
+    }
+
+    #[test]
+    fn bad_rules() {
+        for rule in ["", "B3", "B3/S23/C3/X", "B3/B3", "B3/Sx", "B3/S23/C1", "B3/S23/C257", "B1-200/S2", "B3/X2"].iter() {
+            assert!(Rule::parse(rule).is_err(), "{} parsed", rule);
+        }
+    }
+
+    #[test]
+    fn rle_glider() {
+        let pattern = parse_rle("#N Glider\nx = 3, y = 3, rule = B3/S23\nbob$2bo$3o!\n").unwrap();
+        assert_eq!((pattern.width, pattern.height), (3, 3));
+        assert_eq!(pattern.rule, Some("B3/S23".to_owned()));
+        assert_eq!(pattern.cells, vec![(1, 0, 1), (2, 1, 1), (0, 2, 1), (1, 2, 1), (2, 2, 1)]);
+    }
+
+    #[test]
+    fn rle_multi_state() {
+        // A is 1, B 2, pA 25 and a run of two yB is 24 * 10 + 2.
+        let pattern = parse_rle("x = 4, y = 2, rule = B2/S/C300\nA.B$pA2yB!").unwrap();
+        assert_eq!(pattern.cells, vec![(0, 0, 1), (2, 0, 2), (0, 1, 25), (1, 1, 242), (2, 1, 242)]);
+    }
+
+    #[test]
+    fn rle_without_header() {
+        let pattern = parse_rle("3o$\n$o!").unwrap();
+        assert_eq!((pattern.width, pattern.height), (3, 3));
+        assert!(parse_rle("x = 3, y = 3\n3z!").is_err());
+        assert!(parse_rle("x = three, y = 3\n3o!").is_err());
+    }
+}
@@ -1,36 +1,170 @@
 extern crate camera_capture;
+extern crate clap;
 #[macro_use] extern crate colorify;
+extern crate crc32fast;
+extern crate deflate;
 extern crate find_folder;
+extern crate gif;
 extern crate image;
+extern crate libc;
+extern crate notify;
 extern crate ocl;
 extern crate piston_window;
 extern crate rand;
+extern crate rand_chacha;
+extern crate rayon;
 
+mod allcolors;
+mod anim;
+mod backend;
+mod mode;
 mod gpu;
+mod cpu;
+mod sim;
 mod tracer;
+mod video;
 mod cam;
+mod checkpoint;
+mod color;
 mod common;
+mod config;
+mod life;
+mod provenance;
+mod record;
+mod seed;
+mod stencil;
+mod watch;
 
-use rand::Rng;
 use piston_window::{
-    PistonWindow, WindowSettings, OpenGL,
+    PistonWindow, WindowSettings, OpenGL, AdvancedWindow,
     Texture, TextureSettings, Transformed,
-    MouseCursorEvent, RenderEvent, UpdateEvent, ReleaseEvent, ButtonEvent,
+    MouseCursorEvent, RenderEvent, ReleaseEvent, ButtonEvent,
     Button, ButtonState, MouseButton, Key,
 };
+use std::path::Path;
+use std::process;
 use std::thread;
 use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use std::sync::mpsc;
-use common::{Turn, Cursor};
-
-const CAM_ENABLE: bool = true;
+use anim::Animation;
+use common::{Turn, Control, BuildError};
+use cam::CamImg;
+use config::{Config, Command};
+use video::VideoSink;
 
 fn main() {
-    let dims: (u32, u32) = (848, 480); // cam dims
-    // let dims: (u32, u32) = (1000, 500); // elephant dims
-    // let dims: (u32, u32) = (1920 / 2, 1080 / 2);
-    // let dims: (u32, u32) = (1000, 1000);
+    let config = config::from_args();
+    match config.command {
+        Command::Run => run_window(config),
+        Command::Headless{..} => run_headless(config),
+        Command::Devices => gpu::print_devices(),
+        Command::Export{ref frames, ref out} => anim::export(frames, out, config.anim),
+        Command::Replay{ref png, frames} => {
+            let png = png.clone();
+            run_replay(config, &png, frames)
+        },
+        Command::Compare{frames, tolerance} => {
+            if !cpu::compare(&config, frames, tolerance) {
+                process::exit(1);
+            }
+        },
+    }
+}
+
+// Start feeding subject frames from wherever the config says.
+fn start_subject(config: &Config) -> Arc<Mutex<mpsc::Receiver<CamImg>>> {
+    let (tx, rx) = mpsc::sync_channel(1);
+    let subject = config.subject.clone();
+    let dims = config.dims;
+    let fit = config.fit;
+    let looping = config.loop_subject;
+    let tileable = config.tileable;
+    // A resumed run picks up the footage where it was.
+    let skip = match config.resume {
+        Some(ref path) => checkpoint::read_header(path).frame / config.subject_every,
+        None => 0,
+    };
+    thread::Builder::new().name("subject".to_owned()).spawn(move || {
+        cam::source_loop(subject, dims, fit, looping, tileable, skip, tx);
+    }).unwrap();
+    Arc::new(Mutex::new(rx))
+}
+
+// Open the video sink, if any. It outlives sim restarts so the stream stays in one piece.
+fn start_video(config: &Config) -> Option<Arc<Mutex<VideoSink>>> {
+    config.video.as_ref().map(|path| {
+        // With a fixed clock the rate is sim time. Otherwise it's measured.
+        let rate = config.frame_ms.map(|frame_ms| (1000, frame_ms * config.video_every as u32));
+        Arc::new(Mutex::new(VideoSink::create(path, config.dims, rate)))
+    })
+}
+
+// Open the animation, if any. Like the video it outlives sim restarts,
+// which would otherwise start the file over.
+fn start_animation(config: &Config) -> Option<Arc<Mutex<Animation>>> {
+    config.animation.as_ref().map(|path| {
+        printlnc!(white_bold: "animating to {}", path.display());
+        Arc::new(Mutex::new(Animation::create(path, config.dims, config.anim)))
+    })
+}
+
+// Run the sim loop on this thread with no window and no camera.
+// It stops on its own according to the headless options.
+fn run_headless(config: Config) {
+    // Before anything is printed, in case the video takes over stdout.
+    let video = start_video(&config);
+    let animation = start_animation(&config);
+    printlnc!(white_bold: "seed: {}", config.seed);
+    let dims = config.dims;
+    let subject_receiver = start_subject(&config);
+    let white: image::Rgba<u8> = image::Rgba([255u8, 255u8, 255u8, 255u8]);
+    let img_blank: image::ImageBuffer<image::Rgba<u8>, Vec<u8>> = image::ImageBuffer::from_pixel(
+        dims.0, dims.1, white);
+    // Nobody is displaying, so the sim loop never needs to share.
+    let img_canvas_shared = Arc::new(Mutex::new(img_blank));
+    let turn_shared = Arc::new(Mutex::new(Turn::WantDisplay));
+    let cursor_shared = Arc::new(Mutex::new(Default::default()));
+    let build_error_shared = Arc::new(Mutex::new(None));
+    sim::run_loop(
+        config,
+        img_canvas_shared,
+        turn_shared,
+        cursor_shared,
+        subject_receiver,
+        None,
+        build_error_shared,
+        video,
+        animation,
+    );
+}
+
+// Headless up to the frame `png` was saved at, then check the result against it.
+// The settings came from `png` when the config was made.
+fn run_replay(config: Config, png: &Path, frames: u64) {
+    let entries = provenance::read(png);
+    let flags = provenance::get(&entries, provenance::FLAGS).unwrap_or("");
+    printlnc!(white_bold: "replaying {}: headless {} --frames {}", png.display(), flags.replace('\n', " "), frames);
+    if provenance::get(&entries, provenance::RUN) == Some("window") {
+        printlnc!(red: "it was made in a window, so nothing done with the mouse or keys will be replayed");
+    }
+    if !flags.lines().any(|flag| flag == "--frame-ms") {
+        printlnc!(red: "it ran on the wall clock, so the replay can only come close");
+    }
+    let result = config.out_dir.join("result.png");
+    run_headless(config);
+    if !provenance::compare(png, &result) {
+        process::exit(1);
+    }
+}
+
+fn run_window(config: Config) {
+    // Before anything is printed, in case the video takes over stdout.
+    let video = start_video(&config);
+    let animation = start_animation(&config);
+    printlnc!(white_bold: "seed: {}", config.seed);
+    let dims = config.dims;
 
     #[allow(unused_variables)]
     let black: image::Rgba<u8> = image::Rgba([0u8, 0u8, 0u8, 255u8]);
@@ -50,51 +184,84 @@ fn main() {
     let cursor_shared = Arc::new(Mutex::new(Default::default()));
 
     // Start the cam loop
-    let cam_receiver = {
-        let (tx, rx) = mpsc::sync_channel(1);
-        if CAM_ENABLE {
-            thread::Builder::new().name("cam".to_owned()).spawn(move || {
-                cam::cam_loop(dims, tx);
-            }).unwrap();
-        }
-        rx
-    };
+    let subject_receiver = start_subject(&config);
 
-    let (gpu_stop_sender, gpu_stop_receiver) = mpsc::sync_channel(1);
+    let (gpu_control_sender, gpu_control_receiver) = mpsc::channel();
+    let build_error_shared: Arc<Mutex<BuildError>> = Arc::new(Mutex::new(None));
+
+    // Reload kernels when the sources change.
+    {
+        let gpu_control_sender = gpu_control_sender.clone();
+        thread::Builder::new().name("watch".to_owned()).spawn(move || {
+            watch::watch_loop(gpu_control_sender);
+        }).unwrap();
+    }
 
     // Test the cam. Exceptions are easier to debug from out here.
-    // let _ = cam_receiver.recv().expect("cam img");
+    // let _ = subject_receiver.recv().expect("cam img");
+
+    // Set when the window closes so the supervisor stops restarting.
+    let quitting = Arc::new(AtomicBool::new(false));
 
     // Start the gpu loop (with supervisor wrapper)
-    {
+    let gpu_outer = {
+        let quitting = Arc::clone(&quitting);
+        let config = config.clone();
         let img_canvas_shared = Arc::clone(&img_canvas_shared);
         let cursor_shared = Arc::clone(&cursor_shared);
         let turn_shared = Arc::clone(&turn_shared);
-        let cam_receiver = Arc::new(Mutex::new(cam_receiver));
-        let stop_receiver = Arc::new(Mutex::new(gpu_stop_receiver));
+        let build_error_shared = Arc::clone(&build_error_shared);
+        let control_receiver = Arc::new(Mutex::new(gpu_control_receiver));
         thread::Builder::new().name("gpu-outer".to_owned()).spawn(move || {
-            let cam_receiver = Arc::clone(&cam_receiver);
+            let mut resume = config.resume.clone();
+            let checkpoint_path = config.checkpoint.clone();
             loop {
+                let mut config = config.clone();
+                config.resume = resume.clone();
                 let img_canvas_shared = Arc::clone(&img_canvas_shared);
                 let cursor_shared = Arc::clone(&cursor_shared);
                 let turn_shared = Arc::clone(&turn_shared);
-                let cam_receiver = Arc::clone(&cam_receiver);
-                let stop_receiver = Arc::clone(&stop_receiver);
+                let subject_receiver = Arc::clone(&subject_receiver);
+                let control_receiver_inner = Arc::clone(&control_receiver);
+                let build_error_shared_inner = Arc::clone(&build_error_shared);
+                let video = video.clone();
+                let animation = animation.clone();
                 let gpu_thread = thread::Builder::new().name("gpu-inner".to_owned()).spawn(move || {
-                    gpu::run_gpu_loop(
-                        dims,
+                    sim::run_loop(
+                        config,
                         img_canvas_shared,
                         turn_shared,
                         cursor_shared,
-                        cam_receiver,
-                        Some(stop_receiver),
+                        subject_receiver,
+                        Some(control_receiver_inner),
+                        build_error_shared_inner,
+                        video,
+                        animation,
                     );
                 }).unwrap();
-                let _ = gpu_thread.join();
+                // A restart after a stop starts over, one after a crash
+                // carries on from the last checkpoint if there is one.
+                resume = None;
+                if let Err(panic) = gpu_thread.join() {
+                    resume = checkpoint_path.clone().filter(|path| path.exists());
+                    let msg = panic.downcast_ref::<String>().cloned()
+                        .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                        .unwrap_or_else(|| "gpu thread panicked".to_owned());
+                    *build_error_shared.lock().unwrap() = Some(msg);
+                    // Don't restart in a tight loop on a broken build.
+                    // Wait for a change in cl/ (or R) and try again.
+                    printlnc!(red: "gpu thread died, waiting for a change in cl/ to restart");
+                    if control_receiver.lock().unwrap().recv().is_err() {
+                        return;
+                    }
+                }
+                if quitting.load(Ordering::SeqCst) {
+                    return;
+                }
                 thread::sleep(Duration::from_millis(50));
             }
-        }).unwrap();
-    }
+        }).unwrap()
+    };
 
     // // Skip opengl
     // return;
@@ -110,28 +277,26 @@ fn main() {
     let mut texture = Texture::from_image(&mut window.create_texture_context(),
         &img_blank, &TextureSettings::new()).unwrap();
 
+    // What the title says about the build, to only update it on changes.
+    let mut shown_build_error: BuildError = None;
+
     // window.set_lazy(true);
     // let scaleup = 1.5;
     let scaleup = 1.0;
+    // A tileable canvas shows 3x3 copies, shrunk to fit the window, so seams would show.
+    let repeat = if config.tileable { 3 } else { 1 };
+    let mut scale = scaleup;
     while let Some(e) = window.next() {
-        e.update(|_| {
-            const FAKE_MOUSE: bool = false;
-            // Fake raindrop cursor
-            if FAKE_MOUSE && rand::thread_rng().gen::<f32>() < 0.9 {
-                *cursor_shared.lock().unwrap() = Cursor{
-                    enabled: true,
-                    x: rand::thread_rng().gen::<u32>() % dims.0,
-                    y: rand::thread_rng().gen::<u32>() % dims.1,
-                    pressed: false,
-                };
-            }
-        });
-
         e.mouse_cursor(|[x,y]| {
             let mut c = cursor_shared.lock().unwrap();
             c.enabled = true;
-            c.x = (x / scaleup) as u32;
-            c.y = (y / scaleup) as u32;
+            c.x = (x / scale) as u32;
+            c.y = (y / scale) as u32;
+            if repeat > 1 {
+                // Any copy draws on the one canvas.
+                c.x %= dims.0;
+                c.y %= dims.1;
+            }
         });
 
         e.button(|arg| {
@@ -145,12 +310,31 @@ fn main() {
 
         e.release(|button| {
             if button == Button::Keyboard(Key::R) {
-                let _ = gpu_stop_sender.send(());
+                let _ = gpu_control_sender.send(Control::Reload);
 
                 printlnc!(red: "reload");
             }
+            if button == Button::Keyboard(Key::N) {
+                let _ = gpu_control_sender.send(Control::Stop);
+
+                printlnc!(red: "restart");
+            }
+            if button == Button::Keyboard(Key::S) {
+                let _ = gpu_control_sender.send(Control::ToggleRecord);
+            }
         });
 
+        {
+            let build_error = build_error_shared.lock().unwrap().clone();
+            if build_error != shown_build_error {
+                window.set_title(match build_error {
+                    Some(ref log) => format!("piston: image - build failed: {}", first_error_line(log)),
+                    None => "piston: image".to_owned(),
+                });
+                shown_build_error = build_error;
+            }
+        }
+
         e.render(|_| {
             let turn = {*turn_shared.lock().unwrap()};
             if turn == Turn::WantDisplay {
@@ -161,12 +345,39 @@ fn main() {
                 *turn_shared.lock().unwrap() = Turn::WantData;
             }
 
+            let build_failed = shown_build_error.is_some();
             window.draw_2d(&e, |c, g, device| {
                 piston_window::clear(bg_color, g);
-                piston_window::image(&texture, c.transform.scale(scaleup, scaleup), g);
+                if repeat > 1 {
+                    let view = c.get_view_size();
+                    scale = (view[0] / (dims.0 * repeat) as f64).min(view[1] / (dims.1 * repeat) as f64);
+                }
+                for ty in 0..repeat {
+                    for tx in 0..repeat {
+                        let at = c.transform.scale(scale, scale)
+                            .trans((tx * dims.0) as f64, (ty * dims.1) as f64);
+                        piston_window::image(&texture, at, g);
+                    }
+                }
+                if build_failed {
+                    // The details are in the title and the console.
+                    piston_window::rectangle([1.0, 0.0, 0.0, 1.0], [0.0, 0.0, (dims.0 * repeat) as f64 * scale, 6.0], c.transform, g);
+                }
             });
         });
     }
 
-    let _ = gpu_stop_sender.send(());
+    // Let the sim finish what it's writing, like an animation.
+    quitting.store(true, Ordering::SeqCst);
+    let _ = gpu_control_sender.send(Control::Stop);
+    let _ = gpu_outer.join();
+}
+
+// The line of a build log worth putting in a title.
+fn first_error_line(log: &str) -> &str {
+    log.lines()
+        .find(|line| line.contains("error"))
+        .or_else(|| log.lines().find(|line| !line.trim().is_empty()))
+        .unwrap_or("")
+        .trim()
 }
@@ -0,0 +1,478 @@
+use image;
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+use allcolors::AllColorsBackend;
+use backend;
+use backend::{Backend, Canvas, Mask, MaskVal};
+use common::Cursor;
+use config::{Boundary, ColorSpace, Config, Neighborhood};
+use life;
+use seed;
+use seed::{Seeder, Seeds};
+use stencil;
+use stencil::Stencil;
+use sim;
+
+// One argument of a step kernel, in the order the kernel declares them.
+// The gpu backend binds these and keeps the per-frame ones up to date.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KernelArg {
+    // The latest canvas (read_only image2d_t).
+    Canvas,
+    // Where the next canvas goes (write_only image2d_t).
+    OutCanvas,
+    // The latest mask (read_only image2d_t).
+    Mask,
+    // Where the next mask goes (write_only image2d_t).
+    OutMask,
+    // The subject (read_only image2d_t).
+    Subject,
+    // Where growth can go, see stencil.rs (read_only image2d_t).
+    Stencil,
+    // Per-pixel rng counters (global ulong *).
+    RngState,
+    // Milliseconds since the start (uint).
+    TimeMs,
+    // The cursor (uint, uint, uint2).
+    CursorEnabled,
+    CursorPressed,
+    CursorXy,
+    // A constant (int).
+    Int(&'static str, i32),
+    // A constant (float).
+    Float(&'static str, f32),
+}
+
+// What to run each step.
+#[derive(Clone, Debug)]
+pub struct KernelSpec {
+    // File in cl/.
+    pub file: &'static str,
+    pub name: &'static str,
+    pub args: Vec<KernelArg>,
+    pub global_work_size: (u32, u32),
+}
+
+impl KernelSpec {
+    pub fn has_arg(&self, arg: KernelArg) -> bool {
+        self.args.contains(&arg)
+    }
+}
+
+// A kind of simulation: which kernel runs, what it starts from,
+// and what the host does around each step.
+pub trait Mode {
+    fn name(&self) -> &'static str;
+
+    // The file in cl/ and the kernel that do the work, to record with results.
+    fn kernel(&self) -> (&'static str, &'static str);
+
+    fn create_backend(&self, config: &Config, canvas: Canvas, mask: Mask, subject: &Canvas) -> Box<dyn Backend>;
+
+    // The canvas before the first step.
+    fn initial_canvas(&self, config: &Config, _subject: &Canvas) -> Canvas {
+        white_canvas(config.dims)
+    }
+
+    // The mask before the first step.
+    fn initial_mask(&self, config: &Config) -> Mask {
+        image::ImageBuffer::new(config.dims.0, config.dims.1)
+    }
+
+    // Whether there's nothing left to fill, for --until-filled.
+    fn filled(&self, mask: &Mask) -> bool {
+        !mask.pixels().any(|px| px[0] == 0)
+    }
+
+    // Host side setup after the backend is created.
+    fn start(&mut self, _backend: &mut dyn Backend, _subject: &Canvas) {}
+
+    // Host side work after each step.
+    fn after_step(&mut self, _frame: u64, _backend: &mut dyn Backend, _subject: &Canvas,
+                  _cursor: &Cursor, _rng: &mut ChaCha8Rng) {}
+
+    // Host side state for checkpoints. A resumed run gets it back instead of `start`.
+    fn read_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn write_state(&mut self, _state: &[u8]) {}
+}
+
+pub const MODES: &[&str] = &[
+    "pastiche", "allcolors", "life", "penguins", "clove", "passthru", "gray", "gray-patches",
+];
+
+pub fn create(config: &Config) -> Box<dyn Mode> {
+    let dims = config.dims;
+    match config.mode.as_str() {
+        "pastiche" => Box::new(Grow::new(config, false)),
+        "allcolors" => Box::new(Grow::new(config, true)),
+        "life" => Box::new(Life::new(config)),
+        "penguins" => Box::new(Filter {
+            name: "penguins",
+            from_subject: true,
+            spec: KernelSpec {
+                file: "main.cl",
+                name: "march_penguins",
+                args: vec![KernelArg::Canvas, KernelArg::Int("boundary", config.boundary as i32), KernelArg::OutCanvas],
+                global_work_size: dims,
+            },
+        }),
+        "clove" => Box::new(Filter {
+            name: "clove",
+            from_subject: false,
+            spec: KernelSpec {
+                file: "main.cl",
+                name: "clove",
+                args: vec![KernelArg::OutCanvas],
+                global_work_size: dims,
+            },
+        }),
+        "passthru" => Box::new(Filter {
+            name: "passthru",
+            from_subject: false,
+            spec: image_to_image("main.cl", "passthru", KernelArg::Subject, dims),
+        }),
+        "gray" => Box::new(Filter {
+            name: "gray",
+            from_subject: false,
+            spec: image_to_image("parallel.cl", "rgb2gray_unrolled", KernelArg::Subject, dims),
+        }),
+        "gray-patches" => {
+            // Each work item does a batch x batch patch.
+            let batch = 8;
+            Box::new(Filter {
+                name: "gray-patches",
+                from_subject: false,
+                spec: KernelSpec {
+                    file: "parallel.cl",
+                    name: "rgb2gray_patches",
+                    args: vec![KernelArg::Int("batch_size", batch as i32), KernelArg::Subject, KernelArg::OutCanvas],
+                    global_work_size: ((dims.0 + batch - 1) / batch, (dims.1 + batch - 1) / batch),
+                },
+            })
+        },
+        other => panic!("unknown mode '{}'", other),
+    }
+}
+
+pub fn white_canvas(dims: (u32, u32)) -> Canvas {
+    image::ImageBuffer::from_pixel(dims.0, dims.1, image::Rgba([255u8, 255u8, 255u8, 255u8]))
+}
+
+// A kernel that takes (read_only image2d_t source, write_only image2d_t dest).
+fn image_to_image(file: &'static str, name: &'static str, source: KernelArg, dims: (u32, u32)) -> KernelSpec {
+    KernelSpec {
+        file: file,
+        name: name,
+        args: vec![source, KernelArg::OutCanvas],
+        global_work_size: dims,
+    }
+}
+
+// Growth from seeds: pastiche, or allcolors with its own backend.
+// The seeds come from the --seeds strategies, see seed.rs.
+struct Grow {
+    allcolors: bool,
+    dims: (u32, u32),
+    color_space: ColorSpace,
+    color_distance: f32,
+    boundary: Boundary,
+    seed: u64,
+    seeders: Vec<Box<dyn Seeder>>,
+    // The newest seed generation.
+    generation: MaskVal,
+    stencil: Option<Stencil>,
+}
+
+// The starting seeds' stream, see `sim::frame_rng`.
+const SEEDS_STREAM: u64 = ::std::u64::MAX - 2;
+
+impl Grow {
+    fn new(config: &Config, allcolors: bool) -> Grow {
+        Grow {
+            allcolors: allcolors,
+            dims: config.dims,
+            color_space: config.color_space,
+            color_distance: config.color_distance,
+            boundary: config.boundary,
+            seed: config.seed,
+            seeders: config.seeds.iter()
+                .map(|spec| seed::parse(spec).unwrap_or_else(|err| panic!("{}", err)))
+                .collect(),
+            generation: 0,
+            stencil: stencil::load(config),
+        }
+    }
+}
+
+pub fn pastiche_spec(dims: (u32, u32), color_space: ColorSpace, color_distance: f32, boundary: Boundary) -> KernelSpec {
+    KernelSpec {
+        file: "main.cl",
+        name: "pastiche",
+        args: vec![
+            KernelArg::Canvas,
+            KernelArg::Mask,
+            KernelArg::Subject,
+            KernelArg::Stencil,
+            KernelArg::RngState,
+            KernelArg::TimeMs,
+            KernelArg::CursorEnabled,
+            KernelArg::CursorPressed,
+            KernelArg::CursorXy,
+            KernelArg::Int("color_space", color_space as i32),
+            KernelArg::Float("color_distance", color_distance),
+            KernelArg::Int("boundary", boundary as i32),
+            KernelArg::OutCanvas,
+            KernelArg::OutMask,
+        ],
+        global_work_size: dims,
+    }
+}
+
+impl Mode for Grow {
+    fn name(&self) -> &'static str {
+        if self.allcolors { "allcolors" } else { "pastiche" }
+    }
+
+    fn kernel(&self) -> (&'static str, &'static str) {
+        if self.allcolors {
+            ("main.cl", "allcolors_place")
+        } else {
+            let spec = pastiche_spec(self.dims, self.color_space, self.color_distance, self.boundary);
+            (spec.file, spec.name)
+        }
+    }
+
+    fn create_backend(&self, config: &Config, canvas: Canvas, mask: Mask, subject: &Canvas) -> Box<dyn Backend> {
+        let mut backend: Box<dyn Backend> = if self.allcolors {
+            backend::require_gpu(config, self.name());
+            Box::new(AllColorsBackend::new(config, canvas))
+        } else {
+            backend::create(config, &pastiche_spec(config.dims, config.color_space, config.color_distance, config.boundary), canvas, mask, subject)
+        };
+        if let Some(ref stencil) = self.stencil {
+            backend.set_stencil(stencil);
+        }
+        backend
+    }
+
+    // Walled off pixels never fill.
+    fn filled(&self, mask: &Mask) -> bool {
+        match self.stencil {
+            Some(ref stencil) => !mask.enumerate_pixels()
+                .any(|(x, y, px)| px[0] == 0 && stencil::allows(stencil, x, y)),
+            None => !mask.pixels().any(|px| px[0] == 0),
+        }
+    }
+
+    fn start(&mut self, backend: &mut dyn Backend, subject: &Canvas) {
+        let mut rng = sim::frame_rng(self.seed, SEEDS_STREAM);
+        let mut seeds = Seeds::new(backend, subject, self.boundary, self.stencil.as_ref(), &mut self.generation);
+        for seeder in self.seeders.iter_mut() {
+            seeder.start(&mut seeds, &mut rng);
+        }
+    }
+
+    fn read_state(&self) -> Vec<u8> {
+        self.generation.to_le_bytes().to_vec()
+    }
+
+    fn write_state(&mut self, state: &[u8]) {
+        if state.len() != 4 {
+            panic!("checkpoint {} state is {} bytes, not the 4 of a seed generation", self.name(), state.len());
+        }
+        self.generation = MaskVal::from_le_bytes([state[0], state[1], state[2], state[3]]);
+    }
+
+    fn after_step(&mut self, frame: u64, backend: &mut dyn Backend, subject: &Canvas,
+                  _cursor: &Cursor, rng: &mut ChaCha8Rng) {
+        let mut seeds = Seeds::new(backend, subject, self.boundary, self.stencil.as_ref(), &mut self.generation);
+        for seeder in self.seeders.iter_mut() {
+            seeder.after_step(frame, &mut seeds, rng);
+        }
+    }
+}
+
+// Life-like cellular automata, see life.rs. The mask holds each cell's state.
+struct Life {
+    rule: life::Rule,
+    neighborhood: Neighborhood,
+    radius: u32,
+    boundary: Boundary,
+    live: [u8; 3],
+    dead: [u8; 3],
+    cells: Mask,
+}
+
+// The starting soup's stream, see `sim::frame_rng`.
+const SOUP_STREAM: u64 = ::std::u64::MAX - 1;
+
+impl Life {
+    fn new(config: &Config) -> Life {
+        let options = &config.life;
+        let pattern = options.pattern.as_ref().map(|path| {
+            life::load_rle(path).unwrap_or_else(|err| panic!("life pattern {}", err))
+        });
+        let rule_str = options.rule.clone()
+            .or_else(|| pattern.as_ref().and_then(|pattern| pattern.rule.clone()))
+            .unwrap_or_else(|| "B3/S23".to_owned());
+        let rule = life::Rule::parse(&rule_str).unwrap_or_else(|err| panic!("{}", err));
+        let neighborhood = rule.neighborhood.unwrap_or(options.neighborhood);
+        let neighbors = life::neighbor_count(neighborhood, options.radius);
+        if neighbors > life::MAX_NEIGHBORS {
+            panic!("a {:?} neighborhood of radius {} has {} cells, life handles up to {}",
+                   neighborhood, options.radius, neighbors, life::MAX_NEIGHBORS);
+        }
+        printlnc!(white_bold: "life rule {}, {:?} radius {}", rule_str, neighborhood, options.radius);
+
+        let dims = config.dims;
+        let cells = match pattern {
+            // In the middle, cut off if it doesn't fit.
+            Some(pattern) => {
+                if pattern.width > dims.0 || pattern.height > dims.1 {
+                    printlnc!(red: "the {}x{} pattern doesn't fit {}x{}, cutting it off",
+                              pattern.width, pattern.height, dims.0, dims.1);
+                }
+                let left = (dims.0 as i64 - pattern.width as i64) / 2;
+                let top = (dims.1 as i64 - pattern.height as i64) / 2;
+                let mut cells: Mask = image::ImageBuffer::new(dims.0, dims.1);
+                for &(x, y, state) in pattern.cells.iter() {
+                    let (x, y) = (x as i64 + left, y as i64 + top);
+                    if x >= 0 && y >= 0 && x < dims.0 as i64 && y < dims.1 as i64 {
+                        cells.put_pixel(x as u32, y as u32, image::Luma([state.min(rule.states - 1)]));
+                    }
+                }
+                cells
+            },
+            // Random soup, a quarter alive.
+            None => {
+                let mut rng = sim::frame_rng(config.seed, SOUP_STREAM);
+                image::ImageBuffer::from_fn(dims.0, dims.1, |_, _| {
+                    image::Luma([if rng.gen::<f32>() < 0.25 { 1 } else { 0 }])
+                })
+            },
+        };
+
+        Life {
+            rule: rule,
+            neighborhood: neighborhood,
+            radius: options.radius,
+            boundary: config.boundary,
+            live: options.live,
+            dead: options.dead,
+            cells: cells,
+        }
+    }
+
+    fn spec(&self, dims: (u32, u32)) -> KernelSpec {
+        let packed = |c: [u8; 3]| ((c[0] as i32) << 16) | ((c[1] as i32) << 8) | c[2] as i32;
+        let (birth, survive) = (self.rule.birth, self.rule.survive);
+        KernelSpec {
+            file: "main.cl",
+            name: "life",
+            args: vec![
+                KernelArg::Mask,
+                KernelArg::Int("birth0", birth[0] as i32),
+                KernelArg::Int("birth1", birth[1] as i32),
+                KernelArg::Int("birth2", birth[2] as i32),
+                KernelArg::Int("birth3", birth[3] as i32),
+                KernelArg::Int("survive0", survive[0] as i32),
+                KernelArg::Int("survive1", survive[1] as i32),
+                KernelArg::Int("survive2", survive[2] as i32),
+                KernelArg::Int("survive3", survive[3] as i32),
+                KernelArg::Int("states", self.rule.states as i32),
+                KernelArg::Int("neighborhood", self.neighborhood as i32),
+                KernelArg::Int("radius", self.radius as i32),
+                KernelArg::Int("boundary", self.boundary as i32),
+                KernelArg::Int("live_rgb", packed(self.live)),
+                KernelArg::Int("dead_rgb", packed(self.dead)),
+                KernelArg::OutCanvas,
+                KernelArg::OutMask,
+            ],
+            global_work_size: dims,
+        }
+    }
+
+    // Same colors as `life_color` in main.cl.
+    fn color(&self, state: MaskVal) -> image::Rgba<u8> {
+        let (live, dead) = (self.live, self.dead);
+        let rgb = match state {
+            0 => dead,
+            1 => live,
+            _ => {
+                // Dying cells fade from live towards dead.
+                let t = (state - 1) as f32 / (self.rule.states - 1) as f32;
+                let mix = |i: usize| (live[i] as f32 + (dead[i] as f32 - live[i] as f32) * t).round() as u8;
+                [mix(0), mix(1), mix(2)]
+            },
+        };
+        image::Rgba([rgb[0], rgb[1], rgb[2], 255])
+    }
+}
+
+impl Mode for Life {
+    fn name(&self) -> &'static str {
+        "life"
+    }
+
+    fn kernel(&self) -> (&'static str, &'static str) {
+        ("main.cl", "life")
+    }
+
+    fn create_backend(&self, config: &Config, canvas: Canvas, mask: Mask, subject: &Canvas) -> Box<dyn Backend> {
+        backend::create(config, &self.spec(config.dims), canvas, mask, subject)
+    }
+
+    fn initial_canvas(&self, config: &Config, _subject: &Canvas) -> Canvas {
+        let dims = config.dims;
+        image::ImageBuffer::from_fn(dims.0, dims.1, |x, y| self.color(self.cells.get_pixel(x, y)[0]))
+    }
+
+    fn initial_mask(&self, _config: &Config) -> Mask {
+        self.cells.clone()
+    }
+
+    // Hold the mouse to draw live cells.
+    fn after_step(&mut self, _frame: u64, backend: &mut dyn Backend, _subject: &Canvas,
+                  cursor: &Cursor, _rng: &mut ChaCha8Rng) {
+        if cursor.enabled && cursor.pressed {
+            // The window can be bigger than the canvas.
+            let at = sim::in_bounds(self.boundary, self.cells.dimensions(), cursor.x as i32, cursor.y as i32);
+            if let Some((x, y)) = at {
+                backend.place_pixel(x, y, self.color(1), 1);
+            }
+        }
+    }
+}
+
+// A kernel with no host logic, like an image filter.
+struct Filter {
+    name: &'static str,
+    // Start from the subject instead of a blank canvas.
+    from_subject: bool,
+    spec: KernelSpec,
+}
+
+impl Mode for Filter {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn kernel(&self) -> (&'static str, &'static str) {
+        (self.spec.file, self.spec.name)
+    }
+
+    fn create_backend(&self, config: &Config, canvas: Canvas, mask: Mask, subject: &Canvas) -> Box<dyn Backend> {
+        backend::create(config, &self.spec, canvas, mask, subject)
+    }
+
+    fn initial_canvas(&self, config: &Config, subject: &Canvas) -> Canvas {
+        if self.from_subject {
+            subject.clone()
+        } else {
+            white_canvas(config.dims)
+        }
+    }
+}
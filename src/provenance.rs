@@ -0,0 +1,157 @@
+use crc32fast;
+use image;
+use std::fs;
+use std::path::Path;
+use backend::{Backend, Canvas};
+use cam;
+use config;
+use config::{Command, Config};
+use mode::Mode;
+
+// How a result was made, kept in its PNG as text chunks so it can be made again
+// with `pastiche replay`. Other tools show them too, e.g. `identify -verbose`.
+pub struct Provenance {
+    // The same for every image of a run.
+    entries: Vec<(String, String)>,
+    // The file in cl/ the kernel comes from. Its hash is the backend's latest build.
+    cl_file: &'static str,
+}
+
+// The keys replay needs.
+pub const FLAGS: &str = "Flags";
+pub const FRAME: &str = "Frame";
+pub const RUN: &str = "Run";
+
+impl Provenance {
+    pub fn new(config: &Config, mode: &dyn Mode, backend: &dyn Backend) -> Provenance {
+        let (cl_file, kernel) = mode.kernel();
+        let run = match config.command {
+            Command::Run => "window",
+            _ => "headless",
+        };
+        let entries = vec![
+            ("Software", "pastiche".to_owned()),
+            ("Seed", config.seed.to_string()),
+            ("Kernel", kernel.to_owned()),
+            ("Dims", format!("{}x{}", config.dims.0, config.dims.1)),
+            ("Subject", cam::describe(&config.subject, config.dims)),
+            ("Backend", backend.describe()),
+            (RUN, run.to_owned()),
+            // The step each color takes from its neighbor's.
+            ("Color distance", config.color_distance.to_string()),
+            // One per line, for `config::apply_sim_flags`.
+            (FLAGS, config::sim_flags(config).join("\n")),
+        ];
+        Provenance {
+            entries: entries.into_iter().map(|(key, value)| (key.to_owned(), value)).collect(),
+            cl_file: cl_file,
+        }
+    }
+
+    // Save `canvas` as a PNG with the run's entries and this frame's.
+    pub fn save(&self, path: &Path, canvas: &Canvas, frame: u64, backend: &dyn Backend) {
+        let mut entries = self.entries.clone();
+        if let Some(crc) = backend.source_crc() {
+            entries.push(("Kernel source".to_owned(), format!("{} crc32 {:08x}", self.cl_file, crc)));
+        }
+        entries.push((FRAME.to_owned(), frame.to_string()));
+
+        canvas.save(path).unwrap();
+        let png = fs::read(path).expect("read back png");
+        fs::write(path, with_text(&png, &entries)).expect("write png text");
+    }
+}
+
+// PNG chunks are a length, a 4 byte type, the data and a crc of type and data.
+fn chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    let mut out = Vec::with_capacity(data.len() + 12);
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc.finalize().to_be_bytes());
+    out
+}
+
+// `png` with text chunks added just before the IEND.
+// Plain ascii goes in tEXt, anything else in uncompressed utf-8 iTXt.
+fn with_text(png: &[u8], entries: &[(String, String)]) -> Vec<u8> {
+    // IEND is always the last 12 bytes.
+    let (body, iend) = png.split_at(png.len() - 12);
+    let mut out = body.to_vec();
+    for &(ref key, ref value) in entries {
+        let mut data = key.as_bytes().to_vec();
+        data.push(0);
+        if value.is_ascii() {
+            data.extend_from_slice(value.as_bytes());
+            out.extend(chunk(b"tEXt", &data));
+        } else {
+            // No compression, no language tag, no translated keyword.
+            data.extend_from_slice(&[0, 0, 0, 0]);
+            data.extend_from_slice(value.as_bytes());
+            out.extend(chunk(b"iTXt", &data));
+        }
+    }
+    out.extend_from_slice(iend);
+    out
+}
+
+// The text chunks of a PNG, in order. Compressed ones are skipped.
+pub fn read(path: &Path) -> Vec<(String, String)> {
+    let png = fs::read(path).unwrap_or_else(|err| panic!("read {}: {}", path.display(), err));
+    if !png.starts_with(b"\x89PNG\r\n\x1a\n") {
+        panic!("{} is not a png", path.display());
+    }
+    let mut entries = Vec::new();
+    let mut at = 8;
+    while at + 12 <= png.len() {
+        let len = u32::from_be_bytes([png[at], png[at + 1], png[at + 2], png[at + 3]]) as usize;
+        let kind = &png[at + 4..at + 8];
+        let data = &png[at + 8..(at + 8 + len).min(png.len())];
+        at += len + 12;
+
+        let mut parts = data.splitn(2, |&b| b == 0);
+        let key = String::from_utf8_lossy(parts.next().unwrap_or(&[])).into_owned();
+        let rest = parts.next().unwrap_or(&[]);
+        match kind {
+            // Latin-1, which maps straight onto the first 256 chars.
+            b"tEXt" => entries.push((key, rest.iter().map(|&b| b as char).collect())),
+            b"iTXt" if rest.len() >= 2 && rest[0] == 0 => {
+                // Past the compression flag and method, the language tag and the translated keyword.
+                let mut fields = rest[2..].splitn(3, |&b| b == 0);
+                if let (Some(_), Some(_), Some(text)) = (fields.next(), fields.next(), fields.next()) {
+                    entries.push((key, String::from_utf8_lossy(text).into_owned()));
+                }
+            },
+            _ => {},
+        }
+    }
+    entries
+}
+
+pub fn get<'a>(entries: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    entries.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| v.as_str())
+}
+
+// Whether `replayed` came out the same as `original`, pixel for pixel.
+pub fn compare(original: &Path, replayed: &Path) -> bool {
+    let load = |path: &Path| image::open(path)
+        .unwrap_or_else(|err| panic!("load {}: {}", path.display(), err))
+        .to_rgba();
+    let (a, b) = (load(original), load(replayed));
+    if a.dimensions() != b.dimensions() {
+        printlnc!(red: "{} is {:?} but {} is {:?}", original.display(), a.dimensions(), replayed.display(), b.dimensions());
+        return false;
+    }
+    let differ = a.pixels().zip(b.pixels()).filter(|&(pa, pb)| pa != pb).count();
+    if differ == 0 {
+        printlnc!(green: "{} matches {}", replayed.display(), original.display());
+        true
+    } else {
+        printlnc!(red: "{} differs from {} in {} of {} pixels",
+                  replayed.display(), original.display(), differ, a.width() * a.height());
+        false
+    }
+}
@@ -0,0 +1,125 @@
+use image;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time;
+use anim::Animation;
+use backend::{unorm, Backend, Canvas, Mask};
+use color;
+use config::{Cadence, ColorSpace, Config};
+use provenance::Provenance;
+
+// Writes numbered PNGs of the run as it goes:
+// result_NNNNNN.png, and optionally mask_NNNNNN.png and score_NNNNNN.png,
+// where NNNNNN is the frame number. Results carry the run's settings, see provenance.rs.
+// With --animation the canvas also goes into an animation, with or without the PNGs.
+pub struct Recorder {
+    enabled: bool,
+    // Off for --animation without --record, until recording is toggled on.
+    pngs: bool,
+    animation: Option<Arc<Mutex<Animation>>>,
+    dir: PathBuf,
+    every: Cadence,
+    mask: Option<Mask>,
+    score: bool,
+    color_space: ColorSpace,
+    last_save: Option<time::Instant>,
+    saved: u64,
+}
+
+impl Recorder {
+    // The animation is opened by the caller so it lasts across sim restarts.
+    pub fn new(config: &Config, animation: Option<Arc<Mutex<Animation>>>) -> Recorder {
+        let dims = config.dims;
+        Recorder {
+            enabled: config.save_enabled || animation.is_some(),
+            pngs: config.save_enabled || animation.is_none(),
+            animation: animation,
+            dir: config.out_dir.clone(),
+            every: config.save_every,
+            mask: if config.save_mask {
+                Some(image::ImageBuffer::new(dims.0, dims.1))
+            } else {
+                None
+            },
+            score: config.save_score,
+            color_space: config.color_space,
+            last_save: None,
+            saved: 0,
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+        // With a wall clock cadence, save right away.
+        self.last_save = None;
+        if self.enabled {
+            self.pngs = true;
+            printlnc!(green: "recording to {}", self.dir.display());
+        } else {
+            printlnc!(royal_blue: "recording off, {} frames saved", self.saved);
+        }
+    }
+
+    // Whether this frame should be saved.
+    pub fn due(&self, frame: u64) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        match (self.every, self.last_save) {
+            (Cadence::Frames(n), _) => frame % n == 0,
+            (Cadence::Millis(_), None) => true,
+            (Cadence::Millis(ms), Some(last)) => last.elapsed() >= time::Duration::from_millis(ms),
+        }
+    }
+
+    // `canvas` must be fresh from the backend.
+    pub fn save(&mut self, frame: u64, canvas: &Canvas, backend: &mut dyn Backend, subject: &Canvas,
+                provenance: &Provenance) {
+        if let Some(ref animation) = self.animation {
+            animation.lock().unwrap().add(canvas);
+        }
+        self.last_save = Some(time::Instant::now());
+        self.saved += 1;
+        if !self.pngs {
+            return;
+        }
+
+        if self.saved == 1 {
+            fs::create_dir_all(&self.dir).expect("create out dir");
+        }
+        provenance.save(&self.dir.join(format!("result_{:06}.png", frame)), canvas, frame, &*backend);
+
+        if let Some(ref mut mask) = self.mask {
+            backend.read_mask(mask);
+            // Filled pixels are white, whatever generation they are.
+            let filled: image::GrayImage = image::ImageBuffer::from_fn(mask.width(), mask.height(), |x, y| {
+                image::Luma([if mask.get_pixel(x, y)[0] == 0 { 0 } else { 255 }])
+            });
+            filled.save(&self.dir.join(format!("mask_{:06}.png", frame))).unwrap();
+        }
+
+        if self.score {
+            score_image(canvas, subject, self.color_space)
+                .save(&self.dir.join(format!("score_{:06}.png", frame))).unwrap();
+        }
+    }
+}
+
+// How far apart two pixels are in `space`, 0 to 255.
+fn pixel_distance(a: &image::Rgba<u8>, b: &image::Rgba<u8>, space: ColorSpace) -> u32 {
+    if let ColorSpace::Srgb = space {
+        // Mean absolute difference of the rgb channels, rounded down.
+        let sum: u32 = (0..3).map(|i| (a[i] as i32 - b[i] as i32).abs() as u32).sum();
+        return sum / 3;
+    }
+    let (a, b) = (unorm(a), unorm(b));
+    (color::distance([a[0], a[1], a[2]], [b[0], b[1], b[2]], space) * 255.0).round() as u32
+}
+
+// How far each pixel is from the subject, brighter is further.
+fn score_image(canvas: &Canvas, subject: &Canvas, space: ColorSpace) -> image::GrayImage {
+    image::ImageBuffer::from_fn(canvas.width(), canvas.height(), |x, y| {
+        image::Luma([pixel_distance(canvas.get_pixel(x, y), subject.get_pixel(x, y), space) as u8])
+    })
+}
@@ -0,0 +1,545 @@
+use std::path::PathBuf;
+use image;
+use image::imageops;
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+use backend::{Backend, Canvas, MaskVal};
+use config::{self, Boundary};
+use sim;
+use stencil;
+use stencil::Stencil;
+
+// Where the growing modes put seeds, from --seeds.
+//
+// A strategy is NAME or NAME:KEY=VALUE,... and --seeds can be given more than
+// once to run several. Positions are pixels or a percentage of the dims ("50%").
+// Seeds take the subject's color under them unless they have color=RRGGBB.
+//
+//   center  dx dy               one seed in the middle, moved over by dx, dy
+//   grid    cols rows           a seed in the middle of each cell of a grid
+//   rain    every count         count seeds at random spots every `every` frames
+//   line    x0 y0 x1 y1 step every
+//                               seeds step apart from (x0, y0) to (x1, y1), all at
+//                               the start or one every `every` frames
+//   image   file                a seed at each pixel of file that isn't black,
+//                               in that pixel's color
+//   edges   count threshold every
+//                               count seeds on the subject's edges at the start,
+//                               and again every `every` frames if set
+
+// Without --seeds: two seeds next to each other and a drop every 100 frames.
+pub const DEFAULT: &[&str] = &["center", "center:dx=80", "rain"];
+
+// What the old --xmas-tree did: a column of green seeds, one every 50 frames.
+pub const XMAS_TREE: &str = "line:x0=50%,y0=65%,x1=50%,y1=20%,step=50,every=50,color=64ba64";
+
+pub trait Seeder {
+    // Before the first step.
+    fn start(&mut self, _seeds: &mut Seeds, _rng: &mut ChaCha8Rng) {}
+
+    // After each step. Resumed runs skip `start`, so this goes by the frame number alone.
+    fn after_step(&mut self, _frame: u64, _seeds: &mut Seeds, _rng: &mut ChaCha8Rng) {}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SeedColor {
+    Subject,
+    Rgb([u8; 3]),
+}
+
+// What seeders place seeds with.
+pub struct Seeds<'a> {
+    backend: &'a mut dyn Backend,
+    subject: &'a Canvas,
+    boundary: Boundary,
+    stencil: Option<&'a Stencil>,
+    // The newest generation handed out. Where growths meet in pastiche the newer one wins.
+    generation: &'a mut MaskVal,
+}
+
+impl<'a> Seeds<'a> {
+    pub fn new(backend: &'a mut dyn Backend, subject: &'a Canvas, boundary: Boundary,
+               stencil: Option<&'a Stencil>, generation: &'a mut MaskVal) -> Seeds<'a> {
+        Seeds {
+            backend: backend,
+            subject: subject,
+            boundary: boundary,
+            stencil: stencil,
+            generation: generation,
+        }
+    }
+
+    pub fn dims(&self) -> (u32, u32) {
+        self.subject.dimensions()
+    }
+
+    // Newer than every seed so far.
+    pub fn next_generation(&mut self) -> MaskVal {
+        *self.generation += 1;
+        *self.generation
+    }
+
+    // Seeds past the edges wrap around or are dropped, depending on the boundary.
+    // Seeds on the stencil's walls are dropped.
+    pub fn place(&mut self, x: i32, y: i32, color: SeedColor, generation: MaskVal) {
+        if let Some((x, y)) = sim::in_bounds(self.boundary, self.dims(), x, y) {
+            if self.stencil.map_or(false, |stencil| !stencil::allows(stencil, x, y)) {
+                return;
+            }
+            let color = match color {
+                SeedColor::Subject => *self.subject.get_pixel(x, y),
+                SeedColor::Rgb(rgb) => image::Rgba([rgb[0], rgb[1], rgb[2], 255]),
+            };
+            self.backend.place_pixel(x, y, color, generation);
+        }
+    }
+}
+
+// A position along one axis.
+#[derive(Clone, Copy, Debug)]
+enum Pos {
+    Pixels(i32),
+    Percent(f32),
+}
+
+impl Pos {
+    fn parse(s: &str) -> Result<Pos, String> {
+        if s.ends_with('%') {
+            let p = &s[..s.len() - 1];
+            p.parse::<f32>().map(Pos::Percent).map_err(|e| format!("bad percentage '{}': {}", s, e))
+        } else {
+            s.parse::<i32>().map(Pos::Pixels).map_err(|e| format!("bad position '{}': {}", s, e))
+        }
+    }
+
+    // 0% is the first pixel and 100% the last.
+    fn at(&self, len: u32) -> i32 {
+        match *self {
+            Pos::Pixels(p) => p,
+            Pos::Percent(p) => (p / 100.0 * (len as f32 - 1.0)).round() as i32,
+        }
+    }
+}
+
+fn parse_seed_color(s: &str) -> Result<SeedColor, String> {
+    match s {
+        "subject" => Ok(SeedColor::Subject),
+        _ => config::parse_color(s).map(SeedColor::Rgb),
+    }
+}
+
+fn parse_every(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(0) => Err("every must be at least 1".to_owned()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(format!("bad frame count '{}': {}", s, e)),
+    }
+}
+
+fn parse_count(s: &str) -> Result<u32, String> {
+    s.parse::<u32>().map_err(|e| format!("bad count '{}': {}", s, e))
+}
+
+// The KEY=VALUE part of a strategy. Each key is taken once, leftovers are an error.
+struct Params<'s> {
+    pairs: Vec<(&'s str, &'s str)>,
+}
+
+impl<'s> Params<'s> {
+    fn parse(s: &'s str) -> Result<Params<'s>, String> {
+        let mut pairs = Vec::new();
+        for pair in s.split(',').filter(|pair| !pair.is_empty()) {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => pairs.push((key.trim(), value.trim())),
+                _ => return Err(format!("expected KEY=VALUE, got '{}'", pair)),
+            }
+        }
+        Ok(Params { pairs: pairs })
+    }
+
+    fn take<T, F>(&mut self, key: &str, parse: F) -> Result<Option<T>, String>
+        where F: Fn(&str) -> Result<T, String>
+    {
+        match self.pairs.iter().position(|&(k, _)| k == key) {
+            Some(i) => {
+                let (_, value) = self.pairs.remove(i);
+                parse(value).map(Some).map_err(|e| format!("{}: {}", key, e))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn or<T, F>(&mut self, key: &str, default: T, parse: F) -> Result<T, String>
+        where F: Fn(&str) -> Result<T, String>
+    {
+        self.take(key, parse).map(|value| value.unwrap_or(default))
+    }
+
+    fn color(&mut self) -> Result<SeedColor, String> {
+        self.or("color", SeedColor::Subject, parse_seed_color)
+    }
+
+    fn done(&self) -> Result<(), String> {
+        match self.pairs.first() {
+            Some(&(key, _)) => Err(format!("unknown setting '{}'", key)),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn parse(spec: &str) -> Result<Box<dyn Seeder>, String> {
+    let mut parts = spec.splitn(2, ':');
+    let name = parts.next().unwrap().trim();
+    let mut params = Params::parse(parts.next().unwrap_or(""))
+        .map_err(|e| format!("bad seeds '{}': {}", spec, e))?;
+    let seeder = parse_seeder(name, &mut params)
+        .and_then(|seeder| params.done().map(|_| seeder))
+        .map_err(|e| format!("bad seeds '{}': {}", spec, e))?;
+    Ok(seeder)
+}
+
+fn parse_seeder(name: &str, p: &mut Params) -> Result<Box<dyn Seeder>, String> {
+    Ok(match name {
+        "center" => Box::new(Center {
+            dx: p.or("dx", Pos::Pixels(0), Pos::parse)?,
+            dy: p.or("dy", Pos::Pixels(0), Pos::parse)?,
+            color: p.color()?,
+        }),
+        "grid" => Box::new(Grid {
+            cols: p.or("cols", 3, parse_count)?.max(1),
+            rows: p.or("rows", 3, parse_count)?.max(1),
+            color: p.color()?,
+        }),
+        "rain" => Box::new(Rain {
+            every: p.or("every", 100, parse_every)?,
+            count: p.or("count", 1, parse_count)?,
+            color: p.color()?,
+        }),
+        // A bar across the middle by default.
+        "line" => Box::new(Line {
+            from: (p.or("x0", Pos::Percent(0.0), Pos::parse)?, p.or("y0", Pos::Percent(50.0), Pos::parse)?),
+            to: (p.or("x1", Pos::Percent(100.0), Pos::parse)?, p.or("y1", Pos::Percent(50.0), Pos::parse)?),
+            step: p.or("step", 1.0, |s| match s.parse::<f32>() {
+                Ok(step) if step > 0.0 => Ok(step),
+                Ok(_) => Err("step must be positive".to_owned()),
+                Err(e) => Err(format!("bad step '{}': {}", s, e)),
+            })?,
+            every: p.take("every", parse_every)?,
+            color: p.color()?,
+        }),
+        "image" => Box::new(SeedImage {
+            file: p.take("file", |s| Ok(PathBuf::from(s)))?.ok_or("image needs file=PATH")?,
+            color: p.take("color", parse_seed_color)?,
+        }),
+        "edges" => Box::new(Edges {
+            count: p.or("count", 20, parse_count)?,
+            threshold: p.or("threshold", 0.25, |s| s.parse::<f32>().map_err(|e| format!("bad threshold '{}': {}", s, e)))?,
+            every: p.take("every", parse_every)?,
+            color: p.color()?,
+        }),
+        other => return Err(format!("unknown strategy '{}', expected center, grid, rain, line, image or edges", other)),
+    })
+}
+
+struct Center {
+    dx: Pos,
+    dy: Pos,
+    color: SeedColor,
+}
+
+impl Seeder for Center {
+    fn start(&mut self, seeds: &mut Seeds, _rng: &mut ChaCha8Rng) {
+        let dims = seeds.dims();
+        let generation = seeds.next_generation();
+        let (x, y) = ((dims.0 / 2) as i32 + self.dx.at(dims.0), (dims.1 / 2) as i32 + self.dy.at(dims.1));
+        seeds.place(x, y, self.color, generation);
+    }
+}
+
+struct Grid {
+    cols: u32,
+    rows: u32,
+    color: SeedColor,
+}
+
+impl Seeder for Grid {
+    fn start(&mut self, seeds: &mut Seeds, _rng: &mut ChaCha8Rng) {
+        let dims = seeds.dims();
+        let generation = seeds.next_generation();
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                let x = (2 * col + 1) * dims.0 / (2 * self.cols);
+                let y = (2 * row + 1) * dims.1 / (2 * self.rows);
+                seeds.place(x as i32, y as i32, self.color, generation);
+            }
+        }
+    }
+}
+
+struct Rain {
+    every: u64,
+    count: u32,
+    color: SeedColor,
+}
+
+impl Seeder for Rain {
+    fn after_step(&mut self, frame: u64, seeds: &mut Seeds, rng: &mut ChaCha8Rng) {
+        if frame % self.every != 0 {
+            return;
+        }
+        let dims = seeds.dims();
+        for _ in 0..self.count {
+            let (x, y) = (rng.gen_range(0, dims.0), rng.gen_range(0, dims.1));
+            let generation = seeds.next_generation();
+            seeds.place(x as i32, y as i32, self.color, generation);
+        }
+    }
+}
+
+struct Line {
+    from: (Pos, Pos),
+    to: (Pos, Pos),
+    // Roughly, so both ends get a seed.
+    step: f32,
+    every: Option<u64>,
+    color: SeedColor,
+}
+
+impl Line {
+    fn points(&self, dims: (u32, u32)) -> Vec<(i32, i32)> {
+        let (x0, y0) = (self.from.0.at(dims.0), self.from.1.at(dims.1));
+        let (x1, y1) = (self.to.0.at(dims.0), self.to.1.at(dims.1));
+        let len = (x1 - x0).abs().max((y1 - y0).abs()) as f32;
+        let n = (len / self.step).round() as i32;
+        if n == 0 {
+            return vec![(x0, y0)];
+        }
+        let lerp = |a: i32, b: i32, i: i32| a + ((b - a) as f32 * i as f32 / n as f32).round() as i32;
+        (0..n + 1).map(|i| (lerp(x0, x1, i), lerp(y0, y1, i))).collect()
+    }
+}
+
+impl Seeder for Line {
+    fn start(&mut self, seeds: &mut Seeds, _rng: &mut ChaCha8Rng) {
+        if self.every.is_some() {
+            return;
+        }
+        let generation = seeds.next_generation();
+        for (x, y) in self.points(seeds.dims()) {
+            seeds.place(x, y, self.color, generation);
+        }
+    }
+
+    fn after_step(&mut self, frame: u64, seeds: &mut Seeds, _rng: &mut ChaCha8Rng) {
+        let every = match self.every {
+            Some(every) if frame % every == 0 => every,
+            _ => return,
+        };
+        if let Some(&(x, y)) = self.points(seeds.dims()).get((frame / every) as usize) {
+            let generation = seeds.next_generation();
+            seeds.place(x, y, self.color, generation);
+        }
+    }
+}
+
+struct SeedImage {
+    file: PathBuf,
+    // The image's own colors if not set.
+    color: Option<SeedColor>,
+}
+
+impl Seeder for SeedImage {
+    fn start(&mut self, seeds: &mut Seeds, _rng: &mut ChaCha8Rng) {
+        let dims = seeds.dims();
+        let mut points = image::open(&self.file)
+            .unwrap_or_else(|err| panic!("seed image {}: {}", self.file.display(), err))
+            .to_rgba();
+        if points.dimensions() != dims {
+            printlnc!(royal_blue: "seed image {} is {}x{}, stretching it to {}x{}", self.file.display(),
+                      points.width(), points.height(), dims.0, dims.1);
+            points = imageops::resize(&points, dims.0, dims.1, imageops::FilterType::Nearest);
+        }
+        let generation = seeds.next_generation();
+        for (x, y, p) in points.enumerate_pixels() {
+            if p[3] == 0 || (p[0] == 0 && p[1] == 0 && p[2] == 0) {
+                continue;
+            }
+            let color = self.color.unwrap_or(SeedColor::Rgb([p[0], p[1], p[2]]));
+            seeds.place(x as i32, y as i32, color, generation);
+        }
+    }
+}
+
+struct Edges {
+    count: u32,
+    // Of the gradient, where a sharp black to white edge is 1.
+    threshold: f32,
+    every: Option<u64>,
+    color: SeedColor,
+}
+
+impl Edges {
+    fn place(&self, seeds: &mut Seeds, rng: &mut ChaCha8Rng) {
+        let edges = edge_pixels(seeds.subject, self.threshold);
+        if edges.is_empty() {
+            printlnc!(royal_blue: "no subject edges over {} to seed", self.threshold);
+            return;
+        }
+        let generation = seeds.next_generation();
+        for _ in 0..self.count {
+            let (x, y) = edges[rng.gen_range(0, edges.len())];
+            seeds.place(x as i32, y as i32, self.color, generation);
+        }
+    }
+}
+
+impl Seeder for Edges {
+    fn start(&mut self, seeds: &mut Seeds, rng: &mut ChaCha8Rng) {
+        self.place(seeds, rng);
+    }
+
+    fn after_step(&mut self, frame: u64, seeds: &mut Seeds, rng: &mut ChaCha8Rng) {
+        match self.every {
+            Some(every) if frame > 0 && frame % every == 0 => self.place(seeds, rng),
+            _ => {},
+        }
+    }
+}
+
+// Pixels where the subject's brightness changes by at least `threshold`, by Sobel.
+fn edge_pixels(subject: &Canvas, threshold: f32) -> Vec<(u32, u32)> {
+    let (w, h) = subject.dimensions();
+    let luma = |x: i32, y: i32| {
+        let p = subject.get_pixel(x.max(0).min(w as i32 - 1) as u32, y.max(0).min(h as i32 - 1) as u32);
+        (0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32) / 255.0
+    };
+    let mut edges = Vec::new();
+    for y in 0..h as i32 {
+        for x in 0..w as i32 {
+            let gx = luma(x + 1, y - 1) + 2.0 * luma(x + 1, y) + luma(x + 1, y + 1)
+                - luma(x - 1, y - 1) - 2.0 * luma(x - 1, y) - luma(x - 1, y + 1);
+            let gy = luma(x - 1, y + 1) + 2.0 * luma(x, y + 1) + luma(x + 1, y + 1)
+                - luma(x - 1, y - 1) - 2.0 * luma(x, y - 1) - luma(x + 1, y - 1);
+            if (gx * gx + gy * gy).sqrt() / 4.0 >= threshold {
+                edges.push((x as u32, y as u32));
+            }
+        }
+    }
+    edges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{Mask, StepParams};
+
+    // Remembers the seeds instead of growing them.
+    struct Placed(Vec<(u32, u32, [u8; 4], MaskVal)>);
+
+    impl Backend for Placed {
+        fn describe(&self) -> String {
+            "placed".to_owned()
+        }
+        fn set_subject(&mut self, _subject: &Canvas) {}
+        fn place_pixel(&mut self, x: u32, y: u32, color: image::Rgba<u8>, mask_value: MaskVal) {
+            self.0.push((x, y, color.0, mask_value));
+        }
+        fn step(&mut self, _params: &StepParams) {}
+        fn read_canvas(&mut self, _canvas: &mut Canvas) {}
+        fn read_mask(&mut self, _mask: &mut Mask) {}
+    }
+
+    const GRAY: [u8; 4] = [9, 9, 9, 255];
+
+    // Everything `specs` place at the start and over `frames` frames.
+    fn placed(specs: &[&str], dims: (u32, u32), frames: u64) -> Vec<(u32, u32, [u8; 4], MaskVal)> {
+        let subject = Canvas::from_pixel(dims.0, dims.1, image::Rgba(GRAY));
+        let mut seeders: Vec<Box<dyn Seeder>> = specs.iter().map(|spec| parse(spec).unwrap()).collect();
+        let mut backend = Placed(Vec::new());
+        let mut generation = 0;
+        {
+            let mut seeds = Seeds::new(&mut backend, &subject, Boundary::None, None, &mut generation);
+            for seeder in seeders.iter_mut() {
+                seeder.start(&mut seeds, &mut sim::frame_rng(1, 0));
+            }
+            for frame in 0..frames {
+                for seeder in seeders.iter_mut() {
+                    seeder.after_step(frame, &mut seeds, &mut sim::frame_rng(1, frame));
+                }
+            }
+        }
+        backend.0
+    }
+
+    #[test]
+    fn positions() {
+        match Pos::parse("50%") {
+            Ok(Pos::Percent(p)) => assert_eq!(p, 50.0),
+            other => panic!("{:?}", other),
+        }
+        match Pos::parse("-3") {
+            Ok(Pos::Pixels(p)) => assert_eq!(p, -3),
+            other => panic!("{:?}", other),
+        }
+        assert!(Pos::parse("half%").is_err());
+        assert!(Pos::parse("1.5").is_err());
+        assert_eq!(Pos::Percent(0.0).at(11), 0);
+        assert_eq!(Pos::Percent(50.0).at(11), 5);
+        assert_eq!(Pos::Percent(100.0).at(11), 10);
+    }
+
+    #[test]
+    fn accepted() {
+        let specs = [
+            "center", "center:dx=25%,dy=-10,color=00ff00", "grid:cols=4,rows=3", "rain:every=50,count=2",
+            "line", "line:x0=0,y0=10%,x1=99,y1=10%,step=2.5,color=subject", "image:file=dots.png",
+            "edges:count=50,threshold=0.5,every=500", " center : dx = 3 ",
+        ];
+        for spec in specs.iter().chain(DEFAULT.iter()).chain([XMAS_TREE].iter()) {
+            if let Err(err) = parse(spec) {
+                panic!("{}", err);
+            }
+        }
+    }
+
+    #[test]
+    fn rejected() {
+        let specs = [
+            ("sparkle", "unknown strategy 'sparkle'"),
+            ("center:dx", "expected KEY=VALUE"),
+            ("center:dy=1,size=2", "unknown setting 'size'"),
+            ("center:dx=1,dx=2", "unknown setting 'dx'"),
+            ("center:dx=ten", "dx: bad position"),
+            ("center:color=12345", "color: colors must look like RRGGBB"),
+            ("grid:cols=-1", "cols: bad count"),
+            ("rain:every=0", "every must be at least 1"),
+            ("line:step=0", "step must be positive"),
+            ("image", "image needs file=PATH"),
+            ("edges:threshold=high", "bad threshold"),
+        ];
+        for &(spec, why) in specs.iter() {
+            match parse(spec) {
+                Ok(_) => panic!("{} parsed", spec),
+                Err(err) => assert!(err.contains(why), "{}: {}", spec, err),
+            }
+        }
+    }
+
+    // Two seeds next to each other, then a drop every 100 frames.
+    #[test]
+    fn default_seeds() {
+        let seeds = placed(DEFAULT, (200, 100), 201);
+        assert_eq!(&seeds[..2], &[(100, 50, GRAY, 1), (180, 50, GRAY, 2)][..]);
+        let drops: Vec<MaskVal> = seeds[2..].iter().map(|&(_, _, _, generation)| generation).collect();
+        assert_eq!(drops, vec![3, 4, 5]);
+    }
+
+    // A column of green from the bottom up, one every 50 frames.
+    #[test]
+    fn xmas_tree() {
+        let green = [0x64, 0xba, 0x64, 255];
+        assert_eq!(placed(&[XMAS_TREE], (100, 201), 200),
+                   vec![(50, 130, green, 1), (50, 85, green, 2), (50, 40, green, 3)]);
+    }
+}
@@ -0,0 +1,452 @@
+use image;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc::TryRecvError;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use tracer::TimeTracer;
+use std::sync::{Arc,Mutex};
+use std::time;
+use common::{Turn, Cursor, Control, BuildError};
+use std::sync::mpsc;
+use cam;
+use cam::{CamImg};
+use checkpoint;
+use config::{Boundary, Config, Command};
+use mode;
+use provenance::Provenance;
+use anim::Animation;
+use record::Recorder;
+use video::VideoSink;
+use backend::{Canvas, Mask, StepParams};
+
+#[allow(dead_code)]
+fn read_source_image(loco : &str) -> image::ImageBuffer<image::Rgba<u8>, Vec<u8>> {
+    let dyn = image::open(&Path::new(loco)).unwrap();
+    let img = dyn.to_rgba();
+    img
+}
+
+#[allow(dead_code)]
+fn min_pixel<I>(img: &I) -> (u32, u32, u16)
+    where I: image::GenericImage<Pixel=image::Luma<u16>>
+{
+    let (x,y,px) = img.pixels().min_by_key(|&(_,_,v)| v[0]).unwrap();
+    (x, y, px[0])
+}
+
+// Pixels sorted by value.
+// Only consider those pixels where mask is hot.
+// Returns list of (x, y, pixel_value)
+#[allow(dead_code)]
+fn sort_pixels_with_mask<I,M>(img: &I, mask: &M) -> Vec<(u32, u32, u16)>
+    where I: image::GenericImage<Pixel=image::Luma<u16>>,
+          M: image::GenericImage<Pixel=image::Luma<u8>>
+{
+    let mut choices: Vec<(u32,u32,u16)> = img.pixels().zip(mask.pixels()).filter_map(|((x,y,px), (_,_,map_px))| {
+        if map_px[0] > 127u8 {
+            Some((x,y,px[0]))
+        } else {
+            None
+        }
+    }).collect();
+    // .min_by_key(|&(_,_,px)| px)
+    choices.sort_unstable_by_key(|&(_,_,px)| px);
+    choices
+}
+
+// Minimum value pixel in the img.
+// Only consider those pixels where mask is hot.
+// Returns None if there are no viable pixels.
+#[allow(dead_code)]
+fn min_pixel_with_mask<I,M>(img: &I, mask: &M) -> Option<(u32, u32, u16)>
+    where I: image::GenericImage<Pixel=image::Luma<u16>>,
+          M: image::GenericImage<Pixel=image::Luma<u8>>
+{
+    let choices = sort_pixels_with_mask(img, mask);
+    choices.first().map(|x| *x)
+}
+
+// The neighbors of the position that are not filled,
+// past the edges as `boundary` says.
+#[allow(dead_code)]
+fn neighbors_empty(x: u32, y: u32, mask_filled: &Mask, boundary: Boundary) -> Vec<(u32,u32)> {
+    let dims = mask_filled.dimensions();
+    let mut neighbors = vec![];
+    for dx in -1..2 {
+        for dy in -1..2 {
+            let is_self = dx == 0 && dy == 0;
+            if is_self {
+                continue;
+            }
+            if let Some((nx, ny)) = in_bounds(boundary, dims, x as i32 + dx, y as i32 + dy) {
+                if mask_filled.get_pixel(nx, ny)[0] == 0 && !neighbors.contains(&(nx, ny)) {
+                    neighbors.push((nx, ny));
+                }
+            }
+        }
+    }
+    neighbors
+}
+
+// Where the point (x,y) lands on a canvas of `dims` under `boundary`,
+// or None if it falls off. Same as `boundary_loc` in main.cl.
+pub fn in_bounds(boundary: Boundary, dims: (u32, u32), x: i32, y: i32) -> Option<(u32, u32)> {
+    let axis = |v: i32, len: u32| -> Option<u32> {
+        let len = len as i32;
+        let v = match boundary {
+            Boundary::None => if v >= 0 && v < len { Some(v) } else { None },
+            Boundary::Clamp => Some(v.max(0).min(len - 1)),
+            Boundary::Wrap => Some(v.rem_euclid(len)),
+            Boundary::Mirror => {
+                let m = v.rem_euclid(2 * len);
+                Some(if m < len { m } else { 2 * len - 1 - m })
+            },
+        };
+        v.map(|v| v as u32)
+    };
+    match (axis(x, dims.0), axis(y, dims.1)) {
+        (Some(x), Some(y)) => Some((x, y)),
+        _ => None,
+    }
+}
+
+// Host-side randomness for one frame, derived from the seed and frame number
+// alone. Nothing drawn in one frame can shift what another frame draws,
+// so a run can be replayed from any frame.
+// ChaCha rather than StdRng because StdRng may change between rand versions.
+//
+// Streams count up from 0 with the frames. Draws made once per run take their
+// own stream from the top instead, so they don't change what any frame draws:
+// u64::MAX is allcolors' color shuffle, MAX - 1 life's soup, MAX - 2 the seeds.
+pub fn frame_rng(seed: u64, frame: u64) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_stream(frame);
+    rng
+}
+
+fn duration_millis(d: &time::Duration) -> i64 {
+    const MILLIS_PER_SEC: i64 = 1000;
+    const NANOS_PER_MILLI: i32 = 1000_000;
+    return (d.as_secs() as i64 * MILLIS_PER_SEC) + (d.subsec_nanos() as i64 / NANOS_PER_MILLI as i64);
+}
+
+pub fn run_loop(
+    config: Config,
+    img_canvas_shared: Arc<Mutex<Canvas>>,
+    turn_shared: Arc<Mutex<Turn>>,
+    cursor_shared: Arc<Mutex<Cursor>>,
+    subject_rx: Arc<Mutex<mpsc::Receiver<CamImg>>>,
+    control_rx: Option<Arc<Mutex<mpsc::Receiver<Control>>>>,
+    build_error_shared: Arc<Mutex<BuildError>>,
+    video: Option<Arc<Mutex<VideoSink>>>,
+    animation: Option<Arc<Mutex<Animation>>>,
+) {
+    let dims = config.dims;
+
+    // Already fit to the canvas by the subject thread.
+    let mut img_subject: Canvas = cam::convert(subject_rx.lock().unwrap().recv().expect("subject recv"));
+    let subject_live = config.subject.is_live();
+
+    #[allow(unused_variables)]
+    let black: image::Rgba<u8> = image::Rgba([0u8, 0u8, 0u8, 255u8]);
+    #[allow(unused_variables)]
+    let white: image::Rgba<u8> = image::Rgba([255u8, 255u8, 255u8, 255u8]);
+    #[allow(unused_variables)]
+    let red: image::Rgba<u8> = image::Rgba([255u8, 0u8, 0u8, 255u8]);
+    #[allow(unused_variables)]
+    let green: image::Rgba<u8> = image::Rgba([0u8, 255u8, 0u8, 255u8]);
+
+    let mut mode = mode::create(&config);
+    printlnc!(white_bold: "mode: {}", mode.name());
+
+    // The settings already came from the checkpoint when the config was made.
+    let resume = config.resume.as_ref().map(|path| {
+        printlnc!(white_bold: "resuming from {}", path.display());
+        checkpoint::load(path, dims)
+    });
+
+    let mut img_canvas: Canvas = match resume {
+        Some(ref resume) => resume.canvas.clone(),
+        None => mode.initial_canvas(&config, &img_subject),
+    };
+
+    // Glider:
+    // img_canvas.put_pixel(4, 3, white);
+    // img_canvas.put_pixel(4, 4, white);
+    // img_canvas.put_pixel(4, 5, white);
+    // img_canvas.put_pixel(3, 5, white);
+    // img_canvas.put_pixel(2, 4, white);
+
+    // Which pixels are filled, or for life the state of each cell.
+    let mut img_mask_filled: Mask = match resume {
+        Some(ref resume) => resume.mask.clone(),
+        None => mode.initial_mask(&config),
+    };
+
+    // let mut img_score: image::ImageBuffer<image::Luma<u16>, Vec<u16>> = image::ImageBuffer::from_pixel(
+    //     dims.0, dims.1, image::Luma{data: [0u16]});
+
+    let mut backend = mode.create_backend(&config, img_canvas.clone(), img_mask_filled.clone(), &img_subject);
+    printlnc!(white_bold: "backend: {}", backend.describe());
+    let provenance = Provenance::new(&config, &*mode, &*backend);
+    // Made it past the build.
+    *build_error_shared.lock().unwrap() = None;
+
+    match resume {
+        Some(ref resume) => {
+            backend.write_state(&resume.backend);
+            mode.write_state(&resume.mode);
+        },
+        None => mode.start(&mut *backend, &img_subject),
+    }
+
+    let (headless, max_frames, until_filled) = match config.command {
+        Command::Headless{frames, until_filled} => (true, frames, until_filled),
+        Command::Replay{frames, ..} => (true, Some(frames), false),
+        _ => (false, None, false),
+    };
+
+    if headless {
+        fs::create_dir_all(&config.out_dir).expect("create out dir");
+    }
+    let mut recorder = Recorder::new(&config, animation);
+
+    let talk_every = 200;
+    let subject_every = config.subject_every;
+    // Checking for a full canvas needs the mask on the host.
+    let fill_check_every = 100;
+    let mut fake_cursor: Cursor = Default::default();
+    let mut first_frame = 0;
+    // Wall clock time carried over from before a resume.
+    let mut time_offset_ms = 0;
+    if let Some(resume) = resume {
+        fake_cursor = resume.fake_cursor;
+        first_frame = resume.frame;
+        time_offset_ms = resume.time_ms;
+    }
+    // The frame a checkpoint taken now would resume at, and its clock.
+    let mut next_frame = first_frame;
+    let mut next_time_ms = time_offset_ms;
+
+    let start = time::Instant::now();
+
+    'outer: for frame in first_frame.. {
+        let talk: bool = frame % talk_every == 0;
+        // The first frame already has its subject frame.
+        let cam: bool = frame > first_frame && frame % subject_every == 0;
+        // let cam: bool = false;
+
+        let mut tracer = TimeTracer::new("frame");
+
+        if talk { printlnc!(white_bold: "\nFrame: {}", frame) };
+
+        if let Some(max_frames) = max_frames {
+            if frame >= max_frames {
+                printlnc!(royal_blue: "reached {} frames", max_frames);
+                break 'outer;
+            }
+        }
+
+        if let Some(ref control_rx) = control_rx {
+            match control_rx.lock().unwrap().try_recv() {
+                Ok(Control::Stop) => {
+                    printlnc!(red: "gpu stopped");
+                    if let Some(ref path) = config.checkpoint {
+                        checkpoint::save(path, &config, next_frame, next_time_ms, &fake_cursor, &mut *backend, &*mode);
+                    }
+                    return;
+                }
+                Ok(Control::ToggleRecord) => recorder.toggle(),
+                Ok(Control::Reload) => {
+                    match backend.reload() {
+                        Ok(()) => {
+                            printlnc!(green: "reloaded kernels");
+                            *build_error_shared.lock().unwrap() = None;
+                        },
+                        Err(log) => {
+                            printlnc!(red: "reload failed, still running the last good build:\n{}", log);
+                            *build_error_shared.lock().unwrap() = Some(log);
+                        },
+                    }
+                },
+                Err(TryRecvError::Empty) => {},
+                Err(TryRecvError::Disconnected) => {
+                    printlnc!(red: "gpu control receiver disconnected");
+                    return;
+                },
+            }
+        }
+
+        if talk { tracer.stage("cam") };
+
+        if cam {
+            // Recorded subjects wait for their next frame so a run only depends
+            // on the frame number. The camera just gives whatever it has.
+            let received = if subject_live {
+                match subject_rx.lock().unwrap().try_recv() {
+                    Ok(img) => Some(img),
+                    Err(mpsc::TryRecvError::Empty) => None,
+                    Err(mpsc::TryRecvError::Disconnected) => panic!("subject receiver disconnected"),
+                }
+            } else {
+                Some(subject_rx.lock().unwrap().recv().expect("subject receiver disconnected"))
+            };
+            if let Some(img) = received {
+                let img = cam::convert(img);
+                // Finished recordings keep sending their last frame.
+                if img != img_subject {
+                    img_subject = img;
+                    backend.set_subject(&img_subject);
+                    printlnc!(royal_blue: "subject frame");
+                }
+            }
+        }
+
+        let mut rng = frame_rng(config.seed, frame);
+
+        let time_ms = match config.frame_ms {
+            Some(frame_ms) => (frame as u32).wrapping_mul(frame_ms),
+            None => (time_offset_ms + duration_millis(&start.elapsed()) as u64) as u32,
+        };
+
+        let cursor = if config.fake_mouse {
+            // Fake raindrop cursor
+            if rng.gen::<f32>() < 0.9 {
+                fake_cursor = Cursor{
+                    enabled: true,
+                    x: rng.gen_range(0, dims.0),
+                    y: rng.gen_range(0, dims.1),
+                    pressed: false,
+                };
+            }
+            fake_cursor.clone()
+        } else {
+            cursor_shared.lock().unwrap().clone()
+        };
+
+        let params = StepParams {
+            time_ms: time_ms,
+            cursor: cursor,
+        };
+
+        if talk { printlnc!(royal_blue: "Running kernel..."); }
+        if talk { printlnc!(white_bold: "image dims: {:?}", &dims); }
+
+        if talk { tracer.stage("step"); }
+        backend.step(&params);
+
+        if talk { tracer.stage("pick"); }
+
+        if talk { tracer.stage("place"); }
+
+        mode.after_step(frame, &mut *backend, &img_subject, &params.cursor, &mut rng);
+        next_frame = frame + 1;
+        next_time_ms = match config.frame_ms {
+            Some(frame_ms) => next_frame * frame_ms as u64,
+            None => time_offset_ms + duration_millis(&start.elapsed()) as u64,
+        };
+
+        if talk { tracer.stage("cursor"); }
+        // {
+        //     let cursor = cursor_shared.lock().unwrap();
+        //     let (x, y) = (cursor.x, cursor.y);
+        //     if cursor.enabled && in_bounds(Boundary::None, dims, x as i32, y as i32).is_some() {
+        //         let color = if cursor.pressed {
+        //             white
+        //         } else {
+        //             black
+        //         };
+        //         backend.place_pixel(x, y, color, 1);
+        //     }
+        // }
+
+        let want_display = {*turn_shared.lock().unwrap()} == Turn::WantData;
+        let want_save = recorder.due(frame);
+        let want_video = video.is_some() && frame % config.video_every == 0;
+        if want_display || want_save || want_video {
+            if talk { tracer.stage("read image"); }
+            backend.read_canvas(&mut img_canvas);
+        }
+
+        if talk { tracer.stage("save"); }
+
+        if want_save {
+            recorder.save(frame, &img_canvas, &mut *backend, &img_subject, &provenance);
+
+            // img_mask_frontier.save(&Path::new(&format!("mask_frontier_{:06}.png", frame))).unwrap();
+            // img_mask_filled.save(&Path::new(&format!("mask_filled_{:06}.png", frame))).unwrap();
+
+            // {
+            //     let buf: Vec<u8> = img_score.clone().into_raw().iter().map(|px| {
+            //         (px >> 8) as u8
+            //     }).collect();
+            //     let img2: image::ImageBuffer<image::Luma<u8>, Vec<u8>> = image::ImageBuffer::from_raw(
+            //         dims.0, dims.1, buf).unwrap();
+            //     img2
+            // }.save(&Path::new(&format!("score_{:06}.png", frame))).unwrap();
+        }
+
+        if talk { tracer.stage("share"); }
+        if want_video {
+            if let Some(ref video) = video {
+                video.lock().unwrap().add(&img_canvas);
+            }
+        }
+        if want_display {
+            {
+                let mut out = img_canvas_shared.lock().unwrap();
+                *out = img_canvas.clone();
+            }
+            *turn_shared.lock().unwrap() = Turn::WantDisplay;
+        }
+
+        if until_filled && frame % fill_check_every == 0 {
+            backend.read_mask(&mut img_mask_filled);
+            if mode.filled(&img_mask_filled) {
+                printlnc!(royal_blue: "canvas filled after {} frames", frame + 1);
+                break 'outer;
+            }
+        }
+
+        if let (Some(path), Some(every)) = (config.checkpoint.as_ref(), config.checkpoint_every) {
+            if next_frame % every == 0 {
+                checkpoint::save(path, &config, next_frame, next_time_ms, &fake_cursor, &mut *backend, &*mode);
+            }
+        }
+
+        if talk { tracer.finish(); }
+    }
+
+    if let Some(ref path) = config.checkpoint {
+        checkpoint::save(path, &config, next_frame, next_time_ms, &fake_cursor, &mut *backend, &*mode);
+    }
+
+    if config.save_enabled || headless {
+        printlnc!(white_bold: "saving final");
+        backend.read_canvas(&mut img_canvas);
+        // The last frame that ran.
+        let frame = next_frame.max(1) - 1;
+        provenance.save(&config.out_dir.join("result.png"), &img_canvas, frame, &*backend);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_bounds_edges() {
+        let dims = (4, 3);
+        assert_eq!(in_bounds(Boundary::None, dims, 3, 2), Some((3, 2)));
+        assert_eq!(in_bounds(Boundary::None, dims, -1, 0), None);
+        assert_eq!(in_bounds(Boundary::None, dims, 0, 3), None);
+        assert_eq!(in_bounds(Boundary::Clamp, dims, -1, 5), Some((0, 2)));
+        assert_eq!(in_bounds(Boundary::Wrap, dims, -1, 3), Some((3, 0)));
+        assert_eq!(in_bounds(Boundary::Wrap, dims, 9, -4), Some((1, 2)));
+        // Mirrored at the edge, so the edge pixel shows up twice.
+        assert_eq!(in_bounds(Boundary::Mirror, dims, -1, 3), Some((0, 2)));
+        assert_eq!(in_bounds(Boundary::Mirror, dims, 4, -2), Some((3, 1)));
+        assert_eq!(in_bounds(Boundary::Mirror, dims, 8, 6), Some((0, 0)));
+    }
+}
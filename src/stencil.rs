@@ -0,0 +1,45 @@
+use image;
+use cam;
+use config::{Config, Fit, StencilMode};
+
+// Where growth can go, from --stencil. One byte per pixel, 0 where nothing
+// grows and 255 where growth goes as usual. In between is the chance a frontier
+// pixel fills each step it gets the chance, like `open` in the pastiche kernel.
+pub type Stencil = image::ImageBuffer<image::Luma<u8>, Vec<u8>>;
+
+pub fn load(config: &Config) -> Option<Stencil> {
+    let path = config.stencil.as_ref()?;
+    let img = image::open(path)
+        .unwrap_or_else(|err| panic!("stencil {}: {}", path.display(), err))
+        .to_rgba();
+    // Fit like the subject so the two line up, but with walls for bars.
+    let gray = image::ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+        let p = img.get_pixel(x, y);
+        let luma = (0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32) * p[3] as f32 / 255.0;
+        let v = luma.round() as u8;
+        image::Rgb([v, v, v])
+    });
+    let fit = match config.fit {
+        Fit::Letterbox(_) => Fit::Letterbox([0, 0, 0]),
+        other => other,
+    };
+    let gray = cam::fit(gray, config.dims, fit);
+    let mode = config.stencil_mode;
+    Some(image::ImageBuffer::from_fn(config.dims.0, config.dims.1, |x, y| {
+        let v = gray.get_pixel(x, y)[0];
+        image::Luma([match mode {
+            StencilMode::Threshold => if v >= 128 { 255 } else { 0 },
+            StencilMode::Probability => v,
+        }])
+    }))
+}
+
+// Growth goes everywhere.
+pub fn open(dims: (u32, u32)) -> Stencil {
+    image::ImageBuffer::from_pixel(dims.0, dims.1, image::Luma([255]))
+}
+
+// Whether anything can ever grow at (x, y).
+pub fn allows(stencil: &Stencil, x: u32, y: u32) -> bool {
+    stencil.get_pixel(x, y)[0] > 0
+}
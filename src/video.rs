@@ -0,0 +1,167 @@
+use libc;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::time;
+use backend::Canvas;
+
+// Canvas frames as a YUV4MPEG2 stream, for piping into an encoder:
+//   pastiche headless ... --video - | ffmpeg -i - out.mp4
+// https://wiki.multimedia.cx/index.php/YUV4MPEG2
+pub struct VideoSink {
+    out: BufWriter<File>,
+    dims: (u32, u32),
+    // Frames per second as num:den, once known.
+    rate: Option<(u32, u32)>,
+    // Frames held back while measuring the rate, with when they arrived.
+    pending: Vec<(time::Instant, Vec<u8>)>,
+    frames: u64,
+}
+
+// How long to watch the wall clock before settling on a frame rate.
+const MEASURE_FOR: time::Duration = time::Duration::from_secs(2);
+const MEASURE_FRAMES: usize = 120;
+
+impl VideoSink {
+    // `path` is a file, or "-" for stdout.
+    // With a `rate` the header goes out right away,
+    // without one it's measured from how fast frames come in.
+    pub fn create(path: &Path, dims: (u32, u32), rate: Option<(u32, u32)>) -> VideoSink {
+        let file = if path == Path::new("-") {
+            take_stdout()
+        } else {
+            File::create(path).unwrap_or_else(|err| panic!("create {}: {}", path.display(), err))
+        };
+        let mut sink = VideoSink {
+            out: BufWriter::new(file),
+            dims: dims,
+            rate: None,
+            pending: Vec::new(),
+            frames: 0,
+        };
+        if let Some((num, den)) = rate {
+            sink.write_header(reduce(num, den.max(1)));
+        }
+        sink
+    }
+
+    pub fn add(&mut self, canvas: &Canvas) {
+        let frame = to_yuv420(canvas);
+        if self.rate.is_some() {
+            self.write_frame(&frame);
+            return;
+        }
+        let now = time::Instant::now();
+        self.pending.push((now, frame));
+        let first = self.pending[0].0;
+        if now - first >= MEASURE_FOR || self.pending.len() >= MEASURE_FRAMES {
+            self.flush_pending();
+        }
+    }
+
+    // Settle on a rate from the frames so far and write them out.
+    fn flush_pending(&mut self) {
+        let rate = match (self.pending.first(), self.pending.last()) {
+            (Some(&(first, _)), Some(&(last, _))) if last > first => {
+                let secs = (last - first).as_secs_f64();
+                let millifps = ((self.pending.len() - 1) as f64 / secs * 1000.0).round().max(1.0) as u32;
+                reduce(millifps, 1000)
+            },
+            // Not enough to go on.
+            _ => (30, 1),
+        };
+        self.write_header(rate);
+        for (_, frame) in ::std::mem::replace(&mut self.pending, Vec::new()) {
+            self.write_frame(&frame);
+        }
+    }
+
+    fn write_header(&mut self, rate: (u32, u32)) {
+        printlnc!(white_bold: "video: {}x{} at {}:{} fps", self.dims.0, self.dims.1, rate.0, rate.1);
+        write!(self.out, "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C420jpeg\n",
+               self.dims.0, self.dims.1, rate.0, rate.1).expect("write video");
+        self.rate = Some(rate);
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) {
+        self.out.write_all(b"FRAME\n").expect("write video");
+        self.out.write_all(frame).expect("write video");
+        self.frames += 1;
+    }
+}
+
+impl Drop for VideoSink {
+    fn drop(&mut self) {
+        if self.rate.is_none() {
+            self.flush_pending();
+        }
+        let _ = self.out.flush();
+        printlnc!(royal_blue: "video: wrote {} frames", self.frames);
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+fn reduce(num: u32, den: u32) -> (u32, u32) {
+    let d = gcd(num, den).max(1);
+    (num / d, den / d)
+}
+
+// Hand the real stdout to the video and send everything else printed to stderr,
+// so logging can't end up in the stream.
+fn take_stdout() -> File {
+    unsafe {
+        let fd = libc::dup(libc::STDOUT_FILENO);
+        if fd < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            panic!("could not take over stdout for video");
+        }
+        File::from_raw_fd(fd)
+    }
+}
+
+// Planar 4:2:0 in BT.601 studio range, same as cam.rs reads.
+// Chroma is the average of each 2x2 block.
+fn to_yuv420(canvas: &Canvas) -> Vec<u8> {
+    let (w, h) = canvas.dimensions();
+    let (cw, ch) = ((w + 1) / 2, (h + 1) / 2);
+    let mut out = Vec::with_capacity((w * h + 2 * cw * ch) as usize);
+
+    let rgb = |x: u32, y: u32| -> (f32, f32, f32) {
+        let px = canvas.get_pixel(x, y);
+        (px[0] as f32, px[1] as f32, px[2] as f32)
+    };
+    let clamp = |v: f32| v.round().max(0.0).min(255.0) as u8;
+
+    for y in 0..h {
+        for x in 0..w {
+            let (r, g, b) = rgb(x, y);
+            out.push(clamp(16.0 + (65.738 * r + 129.057 * g + 25.064 * b) / 256.0));
+        }
+    }
+
+    let mut cb = Vec::with_capacity((cw * ch) as usize);
+    let mut cr = Vec::with_capacity((cw * ch) as usize);
+    for cy in 0..ch {
+        for cx in 0..cw {
+            let (mut r, mut g, mut b, mut n) = (0.0, 0.0, 0.0, 0.0);
+            for y in (cy * 2)..(cy * 2 + 2).min(h) {
+                for x in (cx * 2)..(cx * 2 + 2).min(w) {
+                    let (pr, pg, pb) = rgb(x, y);
+                    r += pr;
+                    g += pg;
+                    b += pb;
+                    n += 1.0;
+                }
+            }
+            let (r, g, b) = (r / n, g / n, b / n);
+            cb.push(clamp(128.0 + (-37.945 * r - 74.494 * g + 112.439 * b) / 256.0));
+            cr.push(clamp(128.0 + (112.439 * r - 94.154 * g - 18.285 * b) / 256.0));
+        }
+    }
+    out.extend_from_slice(&cb);
+    out.extend_from_slice(&cr);
+    out
+}
@@ -0,0 +1,38 @@
+use std::sync::mpsc;
+use std::time::Duration;
+use find_folder::Search;
+use notify;
+use notify::{Watcher, RecursiveMode, DebouncedEvent};
+use common::Control;
+
+// Ask for a reload whenever a .cl file in cl/ changes.
+pub fn watch_loop(control_tx: mpsc::Sender<Control>) {
+    let cl_dir = Search::ParentsThenKids(3, 3)
+        .for_folder("cl").expect("Error locating 'cl'");
+
+    let (tx, rx) = mpsc::channel();
+    // Editors tend to write a file in a few steps, wait for them to settle.
+    let mut watcher = notify::watcher(tx, Duration::from_millis(200)).expect("create watcher");
+    watcher.watch(&cl_dir, RecursiveMode::NonRecursive).expect("watch cl");
+    printlnc!(white_bold: "watching {}", cl_dir.display());
+
+    for event in rx {
+        let path = match event {
+            DebouncedEvent::Write(path) => path,
+            DebouncedEvent::Create(path) => path,
+            // Some editors save by renaming a temp file over the original.
+            DebouncedEvent::Rename(_, path) => path,
+            DebouncedEvent::Error(err, path) => {
+                printlnc!(red: "watch error {:?}: {}", path, err);
+                continue;
+            },
+            _ => continue,
+        };
+        if path.extension().map_or(false, |ext| ext == "cl") {
+            printlnc!(royal_blue: "{} changed", path.display());
+            if control_tx.send(Control::Reload).is_err() {
+                return;
+            }
+        }
+    }
+}